@@ -11,7 +11,15 @@ header_start:
     ; checksum
     dd 0x100000000 - (0xe85250d6 + 0 + (header_end - header_start))
 
-    ; insert optional multiboot tags here
+    ; framebuffer: ask for a graphics mode, which GRUB may decline with
+    ; gfxpayload=text
+    dw 5    ; type
+    dw 1    ; flags: optional
+    dd 20   ; size
+    dd 1024 ; width
+    dd 768  ; height
+    dd 32   ; depth
+    align 8, db 0
 
     ; required end tag
     dw 0    ; type
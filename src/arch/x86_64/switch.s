@@ -0,0 +1,36 @@
+global switch_context
+global thread_trampoline
+extern thread_entry
+
+section .text
+bits 64
+; switch from the current thread to another
+; IN
+;   rdi: where to save the current thread's stack pointer
+;   rsi: the stack pointer of the thread to switch to
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov [rdi], rsp
+    mov rsp, rsi
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+; a new thread's first switch_context returns here, with the stack aligned
+; as it was at the top
+thread_trampoline:
+    call thread_entry
+.hang:
+    hlt
+    jmp .hang
@@ -0,0 +1,144 @@
+global syscall_entry
+global syscall_exit
+global jump_to_user
+global fork_return
+global kernel_rsp
+extern syscall_handler
+
+; must match the order of the segments in gdt.rs
+USER_DATA equ 0x18 | 3
+USER_CODE equ 0x20 | 3
+; the start of the top user page, USER_END - 4096 in lib.rs
+USER_LIMIT equ 0x7fff_ffff_f000
+
+section .text
+bits 64
+; the syscall instruction lands here with the user's rip in rcx, its rflags
+; in r11 and interrupts masked off by SFMASK
+syscall_entry:
+    mov [user_rsp], rsp
+    mov rsp, [kernel_rsp]
+
+    ; build a SyscallFrame
+    push qword [user_rsp]
+    push r11
+    push rcx
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    call syscall_handler
+
+; also where a forked child first returns to user mode, with rsp pointing
+; at a copy of its parent's frame
+syscall_exit:
+    cli
+    ; sysret to a non-canonical rip faults in ring 0 with the user's rsp
+    ; already loaded, so a rip or rsp in the top user page goes back through
+    ; iretq instead
+    mov rcx, USER_LIMIT
+    cmp [rsp + 13 * 8], rcx
+    jae .iret
+    cmp [rsp + 15 * 8], rcx
+    jae .iret
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop rcx
+    pop r11
+    pop rsp
+    o64 sysret
+
+; with only rax, rip, rflags and rsp left, move rax two slots down and
+; rewrite the rest as an iretq frame, leaving rcx and r11 as sysret would
+.iret:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    sub rsp, 16
+    mov rax, [rsp + 16]
+    mov [rsp], rax
+    mov rcx, [rsp + 24]
+    mov r11, [rsp + 32]
+    mov rax, [rsp + 40]
+    mov [rsp + 8], rcx
+    mov qword [rsp + 16], USER_CODE
+    mov [rsp + 24], r11
+    mov [rsp + 32], rax
+    mov qword [rsp + 40], USER_DATA
+    pop rax
+    iretq
+
+; return to user mode through a saved frame
+; IN
+;   rdi: the SyscallFrame, on the running thread's kernel stack
+fork_return:
+    mov rsp, rdi
+    jmp syscall_exit
+
+; enter user mode for the first time
+; IN
+;   rdi: entry point
+;   rsi: user stack pointer
+jump_to_user:
+    cli
+    push USER_DATA
+    push rsi
+    push 0x202          ; interrupts enabled
+    push USER_CODE
+    push rdi
+
+    xor rax, rax
+    xor rbx, rbx
+    xor rcx, rcx
+    xor rdx, rdx
+    xor rsi, rsi
+    xor rdi, rdi
+    xor rbp, rbp
+    xor r8, r8
+    xor r9, r9
+    xor r10, r10
+    xor r11, r11
+    xor r12, r12
+    xor r13, r13
+    xor r14, r14
+    xor r15, r15
+    iretq
+
+section .data
+; top of the running thread's kernel stack
+kernel_rsp:
+    dq 0
+user_rsp:
+    dq 0
@@ -0,0 +1,93 @@
+global ureg_return
+extern trap_handler
+
+; an exception the CPU pushes no error code for
+%macro trap_noerr 2
+global %1
+%1:
+    push 0
+    push %2
+    jmp trap_common
+%endmacro
+
+; an exception the CPU pushes an error code for
+%macro trap_err 2
+global %1
+%1:
+    push %2
+    jmp trap_common
+%endmacro
+
+section .text
+bits 64
+trap_noerr trap_divide_error, 0
+trap_noerr trap_debug, 1
+trap_noerr trap_nmi, 2
+trap_noerr trap_breakpoint, 3
+trap_noerr trap_overflow, 4
+trap_noerr trap_bound_range, 5
+trap_noerr trap_invalid_opcode, 6
+trap_noerr trap_device_not_available, 7
+trap_err trap_double_fault, 8
+trap_noerr trap_coprocessor_overrun, 9
+trap_err trap_invalid_tss, 10
+trap_err trap_segment_not_present, 11
+trap_err trap_stack_segment, 12
+trap_err trap_general_protection, 13
+trap_err trap_page_fault, 14
+trap_noerr trap_x87_floating_point, 16
+trap_err trap_alignment_check, 17
+trap_noerr trap_machine_check, 18
+trap_noerr trap_simd_floating_point, 19
+trap_noerr trap_virtualization, 20
+trap_err trap_security, 30
+
+; the stubs above have pushed the error code and the vector; save the rest
+; of the registers to make a Ureg
+trap_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    cld
+    call trap_handler
+
+trap_return:
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16         ; the vector and error code
+    iretq
+
+; return through a saved Ureg
+; IN
+;   rdi: the Ureg, on the running thread's kernel stack
+ureg_return:
+    mov rsp, rdi
+    jmp trap_return
@@ -0,0 +1,69 @@
+global start
+
+; system call numbers, see syscall/mod.rs
+EXITS equ 8
+OPEN equ 14
+CREATE equ 22
+PREAD equ 50
+PWRITE equ 51
+
+OREAD equ 0
+OWRITE equ 1
+
+section .text
+bits 64
+; the first user program. Copies /test to /tmp/init.out to show that
+; system calls work, then exits.
+start:
+    mov rax, OPEN
+    mov rdi, test_path
+    mov rsi, OREAD
+    syscall
+    cmp rax, -1
+    je fail
+    mov r12, rax
+
+    mov rax, PREAD
+    mov rdi, r12
+    mov rsi, buf
+    mov rdx, buf_size
+    mov r10, -1
+    syscall
+    cmp rax, -1
+    je fail
+    mov r13, rax
+
+    mov rax, CREATE
+    mov rdi, out_path
+    mov rsi, OWRITE
+    mov rdx, 0o664
+    syscall
+    cmp rax, -1
+    je fail
+
+    mov rdi, rax
+    mov rax, PWRITE
+    mov rsi, buf
+    mov rdx, r13
+    mov r10, -1
+    syscall
+    cmp rax, -1
+    je fail
+
+    mov rax, EXITS
+    mov rdi, 0
+    syscall
+
+fail:
+    mov rax, EXITS
+    mov rdi, fail_msg
+    syscall
+
+section .rodata
+test_path: db "/test", 0
+out_path: db "/tmp/init.out", 0
+fail_msg: db "init failed", 0
+
+section .bss
+buf_size equ 4096
+buf: resb buf_size
@@ -0,0 +1,50 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Timer interrupts per second.
+pub const HZ: u64 = 100;
+
+/// The PIT's input clock in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Programs PIT channel 0 to interrupt `HZ` times a second.
+pub fn init() {
+    assert_has_not_been_called!("clock::init must be called only once");
+
+    let divisor = (PIT_FREQUENCY / HZ) as u16;
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL0);
+    unsafe {
+        // channel 0, lobyte/hibyte, rate generator
+        command.write(0x34);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Counts a timer interrupt, returning the new tick count.
+pub fn tick() -> u64 {
+    (TICKS.fetch_add(1, Ordering::SeqCst) + 1) as u64
+}
+
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst) as u64
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(HZ).saturating_add(999) / 1000
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / HZ
+}
+
+/// Milliseconds since boot.
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
@@ -0,0 +1,432 @@
+//! The console device, `#c`: the keyboard and screen as `cons`, the
+//! kernel log as `kmesg`, and the files Plan 9 keeps there describing the
+//! system and the process reading them.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::format;
+use core::cmp::min;
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
+use crate::{clock, dev, kmesg, memory, nine_p, print, proc, sched};
+
+const PAGE_SIZE: usize = 4096;
+
+/// How much typed input is kept for readers.
+const INPUT_SIZE: usize = 4096;
+
+/// ^D ends a line without a newline, so ending an empty one reads as end
+/// of file.
+const EOT: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+/// ^U throws away the line being typed.
+const KILL: u8 = 0x15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsFile {
+    Swap,
+    Cons,
+    ConsCtl,
+    Time,
+    SysName,
+    User,
+    Pid,
+    HostOwner,
+    Drivers,
+    KMesg,
+}
+
+/// The files in the device's directory, with their permissions.
+const CONS_FILES: &[(&str, ConsFile, u32)] = &[
+    ("cons", ConsFile::Cons, 0o660),
+    ("consctl", ConsFile::ConsCtl, 0o220),
+    ("drivers", ConsFile::Drivers, 0o444),
+    ("hostowner", ConsFile::HostOwner, 0o444),
+    ("kmesg", ConsFile::KMesg, 0o440),
+    ("pid", ConsFile::Pid, 0o444),
+    ("swap", ConsFile::Swap, 0o444),
+    ("sysname", ConsFile::SysName, 0o664),
+    ("time", ConsFile::Time, 0o664),
+    ("user", ConsFile::User, 0o444),
+];
+
+
+/// Characters typed at the console, in a ring. In cooked mode the line
+/// being edited sits at the end, after the `complete` bytes that can be
+/// read; in raw mode everything can.
+struct Input {
+    buf: [u8; INPUT_SIZE],
+    start: usize,
+    len: usize,
+    complete: usize,
+    /// How many opens of consctl have turned raw mode on.
+    raw: usize,
+}
+
+impl Input {
+    const fn new() -> Input {
+        Input {
+            buf: [0; INPUT_SIZE],
+            start: 0,
+            len: 0,
+            complete: 0,
+            raw: 0,
+        }
+    }
+
+    fn room(&self) -> usize {
+        INPUT_SIZE - self.len
+    }
+
+    fn push(&mut self, c: u8) {
+        assert!(self.len < INPUT_SIZE, "console input overflow");
+        self.buf[(self.start + self.len) % INPUT_SIZE] = c;
+        self.len += 1;
+    }
+
+    /// Makes everything typed so far readable.
+    fn commit(&mut self) {
+        self.complete = self.len;
+    }
+
+    fn pop_front(&mut self) -> Option<u8> {
+        if self.complete == 0 {
+            return None;
+        }
+        let c = self.buf[self.start];
+        self.start = (self.start + 1) % INPUT_SIZE;
+        self.len -= 1;
+        self.complete -= 1;
+        Some(c)
+    }
+
+    /// Takes the last character off the line being edited, returning
+    /// whether there was one.
+    fn erase(&mut self) -> bool {
+        while self.len > self.complete {
+            self.len -= 1;
+            // stop at the first byte of a UTF-8 sequence
+            if self.buf[(self.start + self.len) % INPUT_SIZE] & 0xc0 != 0x80 {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input::new());
+static INPUT_READY: sched::Rendez = sched::Rendez::new();
+/// Held by the one reader waiting on `INPUT_READY`.
+static READER: Mutex<()> = Mutex::new(());
+
+lazy_static! {
+    static ref SYSNAME: RwLock<String> = RwLock::new("plan-rust".to_owned());
+}
+
+/// Takes a character typed at the keyboard. Called from interrupt
+/// handlers. In cooked mode the line is echoed and edited here, and only
+/// handed to readers when it ends.
+pub fn kbd_putc(c: char) {
+    let mut utf8 = [0; 4];
+    let bytes = c.encode_utf8(&mut utf8).as_bytes();
+    let mut echo_buf = [0; 4];
+
+    let (wake, echo) = {
+        let mut input = INPUT.lock();
+        if input.raw > 0 {
+            if input.room() >= bytes.len() {
+                bytes.iter().for_each(|&b| input.push(b));
+                input.commit();
+            }
+            (true, None)
+        } else {
+            match bytes[0] {
+                b'\r' | b'\n' | EOT if input.room() > 0 => {
+                    input.push(if bytes[0] == EOT { EOT } else { b'\n' });
+                    input.commit();
+                    (true, if bytes[0] == EOT { None } else { Some("\n") })
+                }
+                BACKSPACE | DELETE => (false, if input.erase() { Some("\x08 \x08") } else { None }),
+                KILL => {
+                    while input.erase() {}
+                    (false, Some("^U\n"))
+                }
+                // keep room to end the line
+                _ if input.room() > bytes.len() => {
+                    bytes.iter().for_each(|&b| input.push(b));
+                    (false, Some(echo_text(c, &mut echo_buf)))
+                }
+                _ => (false, None)
+            }
+        }
+    };
+    if let Some(echo) = echo {
+        print!("{}", echo);
+    }
+    if wake {
+        INPUT_READY.wakeup();
+    }
+}
+
+/// How a typed character is echoed: itself, or for a control character
+/// such as the ESC starting an arrow key's sequence, ^ and a letter, so
+/// the screen doesn't act on it.
+fn echo_text(c: char, buf: &mut [u8; 4]) -> &str {
+    if c < ' ' && c != '\t' {
+        buf[0] = b'^';
+        buf[1] = c as u8 + b'@';
+        core::str::from_utf8(&buf[..2]).unwrap()
+    } else {
+        c.encode_utf8(buf)
+    }
+}
+
+fn set_raw(on: bool) {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        if on {
+            input.raw += 1;
+            input.commit();
+        } else {
+            input.raw -= 1;
+        }
+    });
+    // a half typed line may just have become readable
+    INPUT_READY.wakeup();
+}
+
+/// Reads typed input into `buf`, waiting for some. In cooked mode a read
+/// returns at most one line, and nothing for a line ended by ^D.
+fn read_input(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    let _reader = READER.lock();
+    INPUT_READY.sleep(|| INPUT.lock().complete > 0);
+
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let raw = input.raw > 0;
+        let mut n = 0;
+        while n < buf.len() {
+            match input.pop_front() {
+                None => break,
+                Some(EOT) if !raw => break,
+                Some(c) => {
+                    buf[n] = c;
+                    n += 1;
+                    if c == b'\n' && !raw {
+                        break;
+                    }
+                }
+            }
+        }
+        n
+    })
+}
+
+/// `cons`: reads take typed input and writes go to the screen.
+#[derive(Debug)]
+struct Cons;
+
+impl nine_p::FileRWC for Cons {
+    fn read_at(&mut self, _pos: u64, buf: &mut [u8]) -> nine_p::Result<usize> {
+        Ok(read_input(buf))
+    }
+
+    fn write_at(&mut self, _pos: u64, buf: &[u8]) -> nine_p::Result<usize> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// `consctl`: writing `rawon` turns off echo and line editing until
+/// `rawoff` is written or the file is closed.
+#[derive(Debug)]
+struct ConsCtl {
+    raw: bool,
+}
+
+impl nine_p::FileRWC for ConsCtl {
+    fn read_at(&mut self, _pos: u64, _buf: &mut [u8]) -> nine_p::Result<usize> {
+        Err(nine_p::DevError::PermissionDenied)
+    }
+
+    fn write_at(&mut self, _pos: u64, buf: &[u8]) -> nine_p::Result<usize> {
+        match core::str::from_utf8(buf).map(|s| s.trim()) {
+            Ok("rawon") => {
+                if !self.raw {
+                    self.raw = true;
+                    set_raw(true);
+                }
+            }
+            Ok("rawoff") => {
+                if self.raw {
+                    self.raw = false;
+                    set_raw(false);
+                }
+            }
+            _ => return Err(nine_p::DevError::Str("unknown control message".to_owned()))
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Drop for ConsCtl {
+    fn drop(&mut self) {
+        if self.raw {
+            set_raw(false);
+        }
+    }
+}
+
+/// A file whose text is made afresh for each read, so that reading it
+/// again at offset 0 sees the current value, and that may be written to
+/// change it.
+#[derive(Debug)]
+struct Text {
+    text: fn() -> String,
+    set: Option<fn(String) -> nine_p::Result<()>>,
+}
+
+impl Text {
+    fn new(text: fn() -> String, set: Option<fn(String) -> nine_p::Result<()>>) -> Box<Text> {
+        Box::new(Text { text, set })
+    }
+}
+
+impl nine_p::FileRWC for Text {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> nine_p::Result<usize> {
+        nine_p::FileRWC::read_at(&mut Snapshot((self.text)().into_bytes()), pos, buf)
+    }
+
+    fn write_at(&mut self, _pos: u64, buf: &[u8]) -> nine_p::Result<usize> {
+        let set = self.set.ok_or(nine_p::DevError::PermissionDenied)?;
+        let text = core::str::from_utf8(buf).map_err(|_| nine_p::DevError::Str("bad utf8".to_owned()))?;
+        set(text.trim_end_matches('\n').to_owned())?;
+        Ok(buf.len())
+    }
+}
+
+/// Seconds and nanoseconds, clock ticks and ticks per second, in Plan 9's
+/// format. There's no real time clock yet, so time counts from boot.
+fn time_text() -> String {
+    let ticks = clock::ticks();
+    let ns = clock::ticks_to_ms(ticks) * 1_000_000;
+    format!("{:>11} {:>21} {:>21} {:>21} ", ns / 1_000_000_000, ns, ticks, clock::HZ)
+}
+
+fn set_time(_text: String) -> nine_p::Result<()> {
+    Err(nine_p::DevError::Str("no real time clock to set".to_owned()))
+}
+
+fn sysname_text() -> String {
+    SYSNAME.read().clone()
+}
+
+fn set_sysname(name: String) -> nine_p::Result<()> {
+    *SYSNAME.write() = name;
+    Ok(())
+}
+
+fn user_text() -> String {
+    match sched::current_proc().and_then(proc::get) {
+        Some(p) => p.read().user().to_owned(),
+        None => proc::HOSTOWNER.to_owned()
+    }
+}
+
+fn pid_text() -> String {
+    format!("{:>11} ", sched::current_proc().unwrap_or(0))
+}
+
+fn hostowner_text() -> String {
+    proc::HOSTOWNER.to_owned()
+}
+
+fn drivers_text() -> String {
+    let mut text = String::new();
+    for (name, description) in dev::dev_drivers() {
+        let _ = write!(text, "#{} {}\n", name, description);
+    }
+    text
+}
+
+/// Text made when the file is opened, so that reads see one consistent
+/// snapshot.
+#[derive(Debug)]
+struct Snapshot(Vec<u8>);
+
+impl nine_p::FileRWC for Snapshot {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> nine_p::Result<usize> {
+        let pos = pos as usize;
+        if pos >= self.0.len() {
+            return Err(nine_p::DevError::EOF);
+        }
+
+        let read_len = min(buf.len(), self.0.len() - pos);
+        buf[..read_len].copy_from_slice(&self.0[pos..pos + read_len]);
+        Ok(read_len)
+    }
+
+    fn write_at(&mut self, _pos: u64, _buf: &[u8]) -> nine_p::Result<usize> {
+        Err(nine_p::DevError::PermissionDenied)
+    }
+}
+
+/// Memory use as Plan 9 reports it, followed by the state of the kernel
+/// heap and its slab caches.
+fn swap_text() -> String {
+    let (total, free) = memory::with_controller(|mc| mc.frame_counts());
+    let user = memory::with_controller(|mc| mc.user_frames());
+    let (heap_size, holes) = crate::heap_stats();
+
+    let mut text = String::new();
+    let _ = write!(text, "{} memory\n", total * PAGE_SIZE);
+    let _ = write!(text, "{} pagesize\n", PAGE_SIZE);
+    let _ = write!(text, "{} kernel\n", total - free - user);
+    let _ = write!(text, "{}/{} user\n", user, user + free);
+    let _ = write!(text, "0/0 swap\n");
+    let _ = write!(text, "{}/{} kernel malloc\n", heap_size - holes.free, crate::HEAP_MAX_SIZE);
+    let _ = write!(text, "0/0 kernel draw\n");
+    let _ = write!(text, "{} heap holes\n", holes.holes);
+    let _ = write!(text, "{} largest heap hole\n", holes.largest);
+    for slab in crate::slab_stats().iter() {
+        let objects = slab.slabs * memory::slab::slab_objects(slab.size);
+        let _ = write!(text, "{}/{} slab {} {} hits {} misses\n",
+                       objects - slab.free, objects, slab.size, slab.hits, slab.misses);
+    }
+    text
+}
+
+impl dev::DevFile for ConsFile {
+    const NAME: char = 'c';
+    const DESCRIPTION: &'static str = "console";
+    const FILES: &'static [(&'static str, ConsFile, u32)] = CONS_FILES;
+
+    fn open(self, _access: nine_p::FileAccessMode) -> Box<dyn nine_p::FileRWC> {
+        match self {
+            ConsFile::Swap => Box::new(Snapshot(swap_text().into_bytes())),
+            ConsFile::KMesg => Box::new(Snapshot(kmesg::text().into_bytes())),
+            ConsFile::Cons => Box::new(Cons),
+            ConsFile::ConsCtl => Box::new(ConsCtl { raw: false }),
+            ConsFile::Time => Text::new(time_text, Some(set_time)),
+            ConsFile::SysName => Text::new(sysname_text, Some(set_sysname)),
+            ConsFile::User => Text::new(user_text, None),
+            ConsFile::Pid => Text::new(pid_text, None),
+            ConsFile::HostOwner => Text::new(hostowner_text, None),
+            ConsFile::Drivers => Text::new(drivers_text, None),
+        }
+    }
+
+    fn blocks(self) -> bool {
+        self == ConsFile::Cons
+    }
+}
+
+pub type ConsServer = dev::DevDir<ConsFile>;
@@ -3,14 +3,19 @@ use alloc::collections;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+use alloc::borrow::ToOwned;
+use core::fmt;
 use spin::Mutex;
 use spin::RwLock;
-use crate::nine_p;
+use crate::{nine_p, proc};
 
 #[derive(Clone)]
 pub struct FileServer {
     server: Arc<Mutex<Box<dyn nine_p::NinePServer>>>,
     fid_pool: FidPool,
+    description: &'static str,
 }
 
 impl FileServer {
@@ -21,6 +26,23 @@ impl FileServer {
     pub fn fid_pool(&self) -> &FidPool {
         &self.fid_pool
     }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    /// Makes a request of the server: through its unlocked handle if it has
+    /// one, so a request that waits doesn't hold up the others, otherwise
+    /// with the server locked.
+    pub fn request<T, F>(&self, f: F) -> T
+        where F: FnOnce(&mut dyn nine_p::NinePServer) -> T
+    {
+        let handle = self.server.lock().unlocked();
+        match handle {
+            Some(mut handle) => f(&mut *handle),
+            None => f(&mut **self.server.lock())
+        }
+    }
 }
 
 struct DevDrivers {
@@ -101,14 +123,422 @@ impl FidPool {
 
 pub fn insert_dev_driver(driver: Box<dyn nine_p::NinePServer>) {
     DEV_DRIVERS.write().drivers.insert(driver.name(), FileServer {
+        description: driver.description(),
         server: Arc::new(Mutex::new(driver)),
         fid_pool: FidPool::new()
     });
 }
 
+/// The name and description of every driver. Safe to call from inside a
+/// driver, since none of them are locked to get it.
+pub fn dev_drivers() -> Vec<(char, &'static str)> {
+    DEV_DRIVERS.read().drivers.iter().map(|(name, d)| (*name, d.description())).collect()
+}
+
 pub fn get_dev_driver(name: char) -> Option<FileServer> {
     match DEV_DRIVERS.read().drivers.get(&name) {
         Some(d) => Some(d.clone()),
         None => None
     }
-}
\ No newline at end of file
+}
+/// A fid on a particular server, clunked again when dropped.
+pub struct Chan {
+    server: FileServer,
+    fid: nine_p::Fid,
+    qid: nine_p::qidpool::Qid,
+    path: String,
+    open: bool,
+    removed: bool,
+}
+
+impl Chan {
+    pub fn attach(server: &FileServer, uname: &str, aname: &str) -> nine_p::Result<Chan> {
+        let fid = server.fid_pool().get_fid();
+        let res = server.request(|s| s.attach(fid, nine_p::NO_FID, uname, aname));
+        match res {
+            Ok(qid) => {
+                let name = server.server().lock().name();
+                Ok(Chan {
+                    server: server.clone(),
+                    fid,
+                    qid,
+                    path: format!("#{}{}", name, aname),
+                    open: false,
+                    removed: false,
+                })
+            }
+            Err(e) => {
+                server.fid_pool().clunk_fid(fid);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn server(&self) -> &FileServer {
+        &self.server
+    }
+
+    pub fn fid(&self) -> nine_p::Fid {
+        self.fid
+    }
+
+    pub fn qid(&self) -> nine_p::qidpool::Qid {
+        self.qid
+    }
+
+    /// The name this channel was reached by.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.qid.qid_type().contains(nine_p::qidpool::QidType::DIRECTORY)
+    }
+
+    /// Walks a new channel from this one, failing unless every element
+    /// could be walked.
+    pub fn walk(&self, names: &[&str]) -> nine_p::Result<Chan> {
+        let new_fid = self.server.fid_pool().get_fid();
+        let res = self.server.request(|s| s.walk(self.fid, new_fid, names));
+        let qids = match res {
+            Ok(ref qids) if qids.len() == names.len() => qids.clone(),
+            Ok(_) => {
+                self.server.fid_pool().clunk_fid(new_fid);
+                return Err(nine_p::DevError::NoSuchFile);
+            }
+            Err(e) => {
+                self.server.fid_pool().clunk_fid(new_fid);
+                return Err(e);
+            }
+        };
+
+        let mut path = self.path.clone();
+        for name in names {
+            if !path.ends_with('/') {
+                path.push('/');
+            }
+            path.push_str(name);
+        }
+
+        Ok(Chan {
+            server: self.server.clone(),
+            fid: new_fid,
+            qid: match qids.last() {
+                Some(q) => *q,
+                None => self.qid
+            },
+            path,
+            open: false,
+            removed: false,
+        })
+    }
+
+    pub fn open(&mut self, mode: &nine_p::FileMode) -> nine_p::Result<u32> {
+        let (qid, iounit) = self.server.request(|s| s.open(self.fid, mode))?;
+        self.qid = qid;
+        self.open = true;
+        Ok(iounit)
+    }
+
+    pub fn create(&mut self, name: &str, perm: u32, mode: &nine_p::FileMode) -> nine_p::Result<u32> {
+        let (qid, iounit) = self.server.request(|s| s.create(self.fid, name, perm, mode))?;
+        self.qid = qid;
+        self.open = true;
+        if !self.path.ends_with('/') {
+            self.path.push('/');
+        }
+        self.path.push_str(name);
+        Ok(iounit)
+    }
+
+    pub fn read(&self, offset: u64, count: usize) -> nine_p::Result<Vec<u8>> {
+        let file = self.server.server().lock().blocking_file(self.fid);
+        match file {
+            // may wait, so the server mustn't stay locked
+            Some(mut file) => nine_p::default_read(&mut file, offset, count),
+            None => self.server.request(|s| s.read(self.fid, offset, count))
+        }
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> nine_p::Result<usize> {
+        self.server.request(|s| s.write(self.fid, offset, data))
+    }
+
+    /// Reads every entry of an opened directory.
+    pub fn read_dir(&self) -> nine_p::Result<Vec<nine_p::dir::Dir>> {
+        let mut out = Vec::new();
+        let mut offset = 0;
+        loop {
+            let buf = match self.read(offset, DIR_READ_SIZE) {
+                Ok(buf) => buf,
+                Err(nine_p::DevError::EOF) => break,
+                Err(e) => return Err(e)
+            };
+            if buf.is_empty() {
+                break;
+            }
+            offset += buf.len() as u64;
+            out.extend(nine_p::dir::Dir::list_from_bytes(&buf)?);
+        }
+        Ok(out)
+    }
+
+    pub fn stat(&self) -> nine_p::Result<nine_p::dir::Dir> {
+        self.server.request(|s| s.stat(self.fid))
+    }
+
+    pub fn wstat(&self, dir: &nine_p::dir::Dir) -> nine_p::Result<()> {
+        self.server.request(|s| s.wstat(self.fid, dir))
+    }
+
+    /// Removes the file. The fid is gone afterwards whether or not the
+    /// remove succeeded.
+    pub fn remove(mut self) -> nine_p::Result<()> {
+        self.removed = true;
+        let res = self.server.request(|s| s.remove(self.fid));
+        self.server.fid_pool().clunk_fid(self.fid);
+        res
+    }
+}
+
+const DIR_READ_SIZE: usize = 8192;
+
+impl Drop for Chan {
+    fn drop(&mut self) {
+        if !self.removed {
+            let _ = self.server.request(|s| s.clunk(self.fid));
+            self.server.fid_pool().clunk_fid(self.fid);
+        }
+    }
+}
+
+impl fmt::Debug for Chan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Chan {{ path: {:?}, fid: {}, qid: {:?}, open: {} }}", self.path, self.fid, self.qid, self.open)
+    }
+}
+
+/// The files of a device that serves them in a flat directory, such as
+/// `#c`. DevDir does the serving; the device says what the files are and
+/// what opening one gives.
+pub trait DevFile: Copy + Eq + fmt::Debug + Send + Sync + 'static {
+    const NAME: char;
+    const DESCRIPTION: &'static str;
+    /// The files in the directory, with their permissions.
+    const FILES: &'static [(&'static str, Self, u32)];
+
+    /// What the file reads and writes once opened for `access`.
+    fn open(self, access: nine_p::FileAccessMode) -> Box<dyn nine_p::FileRWC>;
+
+    /// Whether reads of the open file can wait on something, such as
+    /// input, so must be made without the server locked.
+    fn blocks(self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct DevDirFid<F> {
+    /// The file, or None for the directory.
+    file: Option<F>,
+    open: nine_p::File<'static>,
+}
+
+impl<F> DevDirFid<F> {
+    fn is_open(&self) -> bool {
+        self.open.rwc().is_some()
+    }
+}
+
+/// A server for a device's fixed files, all in its root directory.
+#[derive(Debug)]
+pub struct DevDir<F: DevFile> {
+    fids: collections::BTreeMap<nine_p::Fid, DevDirFid<F>>,
+}
+
+impl<F: DevFile> DevDir<F> {
+    pub fn new() -> Self {
+        Self {
+            fids: collections::BTreeMap::new()
+        }
+    }
+
+    fn fid(&self, fid: nine_p::Fid) -> nine_p::Result<&DevDirFid<F>> {
+        self.fids.get(&fid).ok_or(nine_p::DevError::NoFid)
+    }
+
+    fn check_fid_in_use(&self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        if self.fids.contains_key(&fid) {
+            return Err(nine_p::DevError::FidInUse);
+        }
+        Ok(())
+    }
+
+    fn new_fid(&mut self, fid: nine_p::Fid, file: Option<F>) {
+        let open = nine_p::File::new(&Self::name(file), false, None);
+        self.fids.insert(fid, DevDirFid { file, open });
+    }
+
+    fn lookup(name: &str) -> Option<F> {
+        F::FILES.iter().find(|f| f.0 == name).map(|f| f.1)
+    }
+
+    fn entry(file: F) -> (usize, &'static (&'static str, F, u32)) {
+        F::FILES.iter().enumerate().find(|f| (f.1).1 == file).expect("device file not in its table")
+    }
+
+    fn name(file: Option<F>) -> String {
+        match file {
+            None => format!("#{}", F::NAME),
+            Some(file) => (Self::entry(file).1).0.to_owned()
+        }
+    }
+
+    fn perm(file: Option<F>) -> u32 {
+        match file {
+            None => nine_p::dir::DMDIR | 0o555,
+            Some(file) => (Self::entry(file).1).2
+        }
+    }
+
+    fn qid(file: Option<F>) -> nine_p::qidpool::Qid {
+        match file {
+            None => nine_p::qidpool::Qid::new(nine_p::qidpool::QidType::DIRECTORY, 0, 0),
+            Some(file) => nine_p::qidpool::Qid::new(nine_p::qidpool::QidType::FILE, 0, Self::entry(file).0 as u64 + 1)
+        }
+    }
+
+    fn dir(file: Option<F>) -> nine_p::dir::Dir {
+        nine_p::dir::Dir::new(0, 0, &Self::qid(file), Self::perm(file), 0, 0, 0, &Self::name(file),
+                              proc::HOSTOWNER, proc::HOSTOWNER, proc::HOSTOWNER)
+    }
+}
+
+impl<F: DevFile> nine_p::NinePServer for DevDir<F> {
+    fn name(&self) -> char {
+        F::NAME
+    }
+
+    fn description(&self) -> &'static str {
+        F::DESCRIPTION
+    }
+
+    fn auth(&mut self, _afid: nine_p::Fid, _uname: &str, _aname: &str) -> nine_p::Result<nine_p::qidpool::Qid> {
+        Err(nine_p::DevError::AuthNotNeeded)
+    }
+
+    fn attach(&mut self, fid: nine_p::Fid, afid: nine_p::Fid, _uname: &str, aname: &str) -> nine_p::Result<nine_p::qidpool::Qid> {
+        if afid != nine_p::NO_FID {
+            return Err(nine_p::DevError::AuthNotNeeded);
+        }
+        if !aname.is_empty() {
+            return Err(nine_p::DevError::NoSuchFile);
+        }
+        self.check_fid_in_use(fid)?;
+
+        self.new_fid(fid, None);
+        Ok(Self::qid(None))
+    }
+
+    fn clunk(&mut self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        self.fids.remove(&fid).map(|_| ()).ok_or(nine_p::DevError::NoFid)
+    }
+
+    fn open(&mut self, fid: nine_p::Fid, mode: &nine_p::FileMode) -> nine_p::Result<(nine_p::qidpool::Qid, u32)> {
+        let file = {
+            let f = self.fid(fid)?;
+            if f.is_open() {
+                return Err(nine_p::DevError::FileOpen);
+            }
+            f.file
+        };
+        let perm = Self::perm(file);
+        let (read, write) = (perm & 0o400 != 0, perm & 0o200 != 0);
+        let allowed = match mode.access() {
+            nine_p::FileAccessMode::Read => read,
+            nine_p::FileAccessMode::Write => write,
+            nine_p::FileAccessMode::ReadWrite => read && write,
+            nine_p::FileAccessMode::Execute => false
+        };
+        if !allowed || mode.truncate() || mode.remove_on_close() {
+            return Err(nine_p::DevError::PermissionDenied);
+        }
+
+        let rwc: Box<dyn nine_p::FileRWC> = match file {
+            None => {
+                let entries = F::FILES.iter().map(|f| Self::dir(Some(f.1))).collect();
+                Box::new(nine_p::RWCWrapper::new(Box::new(nine_p::dir::ListReader::new(entries))))
+            }
+            Some(file) => file.open(mode.access())
+        };
+
+        let f = self.fids.get_mut(&fid).unwrap();
+        f.open.set_rwc(rwc);
+        f.open.set_access(mode.access());
+        Ok((Self::qid(file), 0))
+    }
+
+    fn walk(&mut self, fid: nine_p::Fid, new_fid: nine_p::Fid, names: &[&str]) -> nine_p::Result<Vec<nine_p::qidpool::Qid>> {
+        let mut file = {
+            let f = self.fid(fid)?;
+            if f.is_open() {
+                return Err(nine_p::DevError::FileOpen);
+            }
+            f.file
+        };
+        if fid != new_fid {
+            self.check_fid_in_use(new_fid)?;
+        }
+
+        let mut out_qid = Vec::new();
+        for name in names {
+            let next = match file {
+                None if *name == ".." => Ok(None),
+                None => Self::lookup(name).map(Some).ok_or(nine_p::DevError::NoSuchFile),
+                Some(_) => Err(nine_p::DevError::NotADir)
+            };
+            match next {
+                Ok(next) => {
+                    file = next;
+                    out_qid.push(Self::qid(file));
+                }
+                Err(e) => {
+                    if out_qid.is_empty() {
+                        return Err(e);
+                    } else {
+                        return Ok(out_qid);
+                    }
+                }
+            }
+        }
+
+        self.new_fid(new_fid, file);
+        Ok(out_qid)
+    }
+
+    fn read(&mut self, fid: nine_p::Fid, offset: u64, count: usize) -> nine_p::Result<Vec<u8>> {
+        let f = self.fids.get_mut(&fid).ok_or(nine_p::DevError::NoFid)?;
+        nine_p::default_read(&mut f.open, offset, count)
+    }
+
+    fn blocking_file(&mut self, fid: nine_p::Fid) -> Option<nine_p::File<'static>> {
+        match self.fids.get(&fid) {
+            Some(f) if f.file.map_or(false, F::blocks) && f.is_open() => Some(f.open.clone()),
+            _ => None
+        }
+    }
+
+    fn write(&mut self, fid: nine_p::Fid, offset: u64, data: &[u8]) -> nine_p::Result<usize> {
+        let f = self.fids.get_mut(&fid).ok_or(nine_p::DevError::NoFid)?;
+        nine_p::default_write(&mut f.open, offset, data)
+    }
+
+    fn remove(&mut self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        self.fids.remove(&fid);
+        Err(nine_p::DevError::PermissionDenied)
+    }
+
+    fn stat(&mut self, fid: nine_p::Fid) -> nine_p::Result<nine_p::dir::Dir> {
+        Ok(Self::dir(self.fid(fid)?.file))
+    }
+}
@@ -0,0 +1,120 @@
+//! Just enough ELF64 to load a static x86_64 executable.
+
+use alloc::vec::Vec;
+use alloc::borrow::ToOwned;
+use byteorder::{LittleEndian, ByteOrder};
+use crate::nine_p;
+use crate::{USER_START, USER_END};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+bitflags! {
+    pub struct SegmentFlags: u32 {
+        const EXECUTE = 1;
+        const WRITE = 2;
+        const READ = 4;
+    }
+}
+
+/// A PT_LOAD program header: `file_size` bytes from `offset` in the file
+/// go at `vaddr`, and the rest of `mem_size` is zeroed.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub flags: SegmentFlags,
+}
+
+#[derive(Debug)]
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+fn bad_exec() -> nine_p::DevError {
+    nine_p::DevError::Str("exec header invalid".to_owned())
+}
+
+impl Elf {
+    /// Reads the headers of an executable, checking that it is a static
+    /// x86_64 binary whose segments lie within the file and in user space
+    /// below its top page, none writable and executable at once or sharing
+    /// a page with another.
+    pub fn parse(image: &[u8]) -> nine_p::Result<Elf> {
+        if image.len() < EHDR_SIZE || &image[..4] != ELF_MAGIC {
+            return Err(bad_exec());
+        }
+        if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB || image[6] != EV_CURRENT {
+            return Err(bad_exec());
+        }
+        if LittleEndian::read_u16(&image[16..]) != ET_EXEC || LittleEndian::read_u16(&image[18..]) != EM_X86_64 {
+            return Err(bad_exec());
+        }
+
+        let entry = LittleEndian::read_u64(&image[24..]);
+        let phoff = LittleEndian::read_u64(&image[32..]) as usize;
+        let phentsize = LittleEndian::read_u16(&image[54..]) as usize;
+        let phnum = LittleEndian::read_u16(&image[56..]) as usize;
+        if phentsize != PHDR_SIZE || phoff.checked_add(phnum * PHDR_SIZE).map_or(true, |end| end > image.len()) {
+            return Err(bad_exec());
+        }
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = &image[phoff + i * PHDR_SIZE..];
+            if LittleEndian::read_u32(ph) != PT_LOAD {
+                continue;
+            }
+            let seg = Segment {
+                flags: SegmentFlags::from_bits_truncate(LittleEndian::read_u32(&ph[4..])),
+                offset: LittleEndian::read_u64(&ph[8..]),
+                vaddr: LittleEndian::read_u64(&ph[16..]),
+                file_size: LittleEndian::read_u64(&ph[32..]),
+                mem_size: LittleEndian::read_u64(&ph[40..]),
+            };
+
+            if seg.file_size > seg.mem_size ||
+                seg.offset.checked_add(seg.file_size).map_or(true, |end| end > image.len() as u64) ||
+                seg.vaddr < USER_START as u64 ||
+                seg.vaddr.checked_add(seg.mem_size).map_or(true, |end| end > USER_END as u64 - PAGE_SIZE) ||
+                seg.flags.contains(SegmentFlags::WRITE | SegmentFlags::EXECUTE) {
+                return Err(bad_exec());
+            }
+            segments.push(seg);
+        }
+
+        // pages are mapped with the flags of the segment they're in, so
+        // no two segments may share one
+        let mut pages: Vec<(u64, u64)> = segments.iter().filter(|s| s.mem_size > 0)
+            .map(|s| (s.vaddr & !(PAGE_SIZE - 1), (s.vaddr + s.mem_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)))
+            .collect();
+        pages.sort();
+        if pages.windows(2).any(|w| w[0].1 > w[1].0) {
+            return Err(bad_exec());
+        }
+
+        if !segments.iter().any(|s| s.flags.contains(SegmentFlags::EXECUTE) &&
+            entry >= s.vaddr && entry < s.vaddr + s.mem_size) {
+            return Err(bad_exec());
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+        })
+    }
+}
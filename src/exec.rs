@@ -0,0 +1,163 @@
+//! Replacing a process's image with an ELF executable.
+
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use alloc::format;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::{elf, memory, nine_p, proc, sched, syscall};
+
+/// The top of the user stack. The page above it is left unmapped.
+pub const USER_STACK_TOP: u64 = 0x7fff_ffff_f000;
+pub const USER_STACK_PAGES: u64 = 16;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The most bytes of arguments, pointers included, we put on a new stack.
+const MAX_ARG_SIZE: usize = 16 * 1024;
+
+const READ_SIZE: usize = 8192;
+
+/// The largest executable we read into the kernel heap.
+const MAX_EXEC_SIZE: usize = 16 * 1024 * 1024;
+
+fn no_memory() -> nine_p::DevError {
+    nine_p::DevError::Str("no free memory".to_owned())
+}
+
+fn read_file(path: &str) -> nine_p::Result<Vec<u8>> {
+    let read_mode = nine_p::FileMode::new(nine_p::FileAccessMode::Read, false, false);
+    let ns = proc::current().read().namespace();
+    let chan = ns.read().open_file(path, &read_mode)?;
+    if chan.is_dir() {
+        return Err(nine_p::DevError::Str("exec of a directory".to_owned()));
+    }
+
+    let mut image = Vec::new();
+    loop {
+        match chan.read(image.len() as u64, READ_SIZE) {
+            Ok(ref buf) if buf.is_empty() => break,
+            Ok(_) if image.len() >= MAX_EXEC_SIZE => {
+                return Err(nine_p::DevError::Str("exec file too large".to_owned()));
+            }
+            Ok(buf) => image.extend_from_slice(&buf),
+            Err(nine_p::DevError::EOF) => break,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(image)
+}
+
+/// Maps the segments, splitting each into the part read from the file and
+/// the zeroed bss after it.
+fn load_segments(space: &memory::AddressSpace, elf: &elf::Elf, image: &[u8]) -> nine_p::Result<()> {
+    for seg in elf.segments.iter().filter(|s| s.mem_size > 0) {
+        let mut flags = PageTableFlags::empty();
+        if seg.flags.contains(elf::SegmentFlags::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !seg.flags.contains(elf::SegmentFlags::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let start = VirtAddr::new(seg.vaddr);
+        let end = start + seg.mem_size;
+        let data_end = (start + seg.file_size).align_up(PAGE_SIZE);
+        let data = &image[seg.offset as usize..(seg.offset + seg.file_size) as usize];
+        let kind = if seg.flags.contains(elf::SegmentFlags::EXECUTE) {
+            memory::SegmentKind::Text
+        } else {
+            memory::SegmentKind::Data
+        };
+
+        if seg.file_size > 0 {
+            let file_end = if data_end < end { data_end } else { end };
+            space.map_segment(kind, start, file_end, flags, data).ok_or_else(no_memory)?;
+        }
+        if data_end < end || seg.file_size == 0 {
+            let bss_start = if seg.file_size == 0 { start } else { data_end };
+            space.add_segment(memory::SegmentKind::Bss, bss_start, end, flags);
+        }
+    }
+    Ok(())
+}
+
+/// Maps the stack and lays out the arguments the way the program's start
+/// code expects them: argc at the stack pointer, then the argv pointers
+/// and a nil, with the strings themselves above.
+fn setup_stack(space: &memory::AddressSpace, args: &[String]) -> nine_p::Result<VirtAddr> {
+    let bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE);
+    space.add_segment(memory::SegmentKind::Stack, bottom, VirtAddr::new(USER_STACK_TOP),
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+
+    let mut sp = USER_STACK_TOP;
+    let mut argv = Vec::with_capacity(args.len());
+    for arg in args {
+        sp -= arg.len() as u64 + 1;
+        unsafe {
+            core::ptr::copy_nonoverlapping(arg.as_ptr(), sp as *mut u8, arg.len());
+            *((sp + arg.len() as u64) as *mut u8) = 0;
+        }
+        argv.push(sp);
+    }
+
+    sp &= !0xf;
+    let words = 1 + argv.len() as u64 + 1;
+    if words % 2 == 1 {
+        sp -= 8;
+    }
+    sp -= words * 8;
+
+    let out = sp as *mut u64;
+    unsafe {
+        *out = argv.len() as u64;
+        for (i, arg) in argv.iter().enumerate() {
+            *out.add(1 + i) = *arg;
+        }
+        *out.add(1 + argv.len()) = 0;
+    }
+    Ok(VirtAddr::new(sp))
+}
+
+/// Replaces the running process's image with the executable at `path`
+/// and enters it. Only returns if the exec failed; past the point where
+/// the old image is thrown away, failure ends the process instead.
+pub fn exec(path: String, args: Vec<String>) -> nine_p::DevError {
+    let image = match read_file(&path) {
+        Ok(image) => image,
+        Err(e) => return e
+    };
+    let elf = match elf::Elf::parse(&image) {
+        Ok(elf) => elf,
+        Err(e) => return e
+    };
+    if args.iter().map(|a| a.len() + 1 + 8).sum::<usize>() > MAX_ARG_SIZE {
+        return nine_p::DevError::Str("arg list too long".to_owned());
+    }
+    let space = match memory::AddressSpace::new() {
+        Some(space) => Arc::new(space),
+        None => return no_memory()
+    };
+
+    // the old image's note handler is gone with it
+    proc::current().write().reset_notify();
+    sched::set_address_space(space.clone());
+    let res = load_segments(&space, &elf, &image).and_then(|_| setup_stack(&space, &args));
+    let entry = VirtAddr::new(elf.entry);
+
+    // nothing on this stack is ever dropped once we leave for user mode
+    drop((space, elf, image, args));
+    match res {
+        Ok(sp) => {
+            drop(path);
+            syscall::enter_user(entry, sp)
+        }
+        Err(e) => {
+            let msg = format!("exec {}: {}", path, e.description());
+            drop(path);
+            proc::exits(&msg)
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! A linear framebuffer set up by the boot loader, drawn on as a grid of
+//! character cells with a bitmap font. The console uses it in place of the
+//! VGA text buffer when booted in a graphics mode.
+
+use byteorder::{ByteOrder, LittleEndian};
+use multiboot2::{BootInformation, FramebufferTag, FramebufferType};
+use crate::FRAMEBUFFER_START;
+
+/// The console font: DejaVu Sans Mono, under the Bitstream Vera license
+/// in font.psf.LICENSE, rendered to 8x16 cells in PSF2 format, with glyphs
+/// for Latin-1.
+static FONT: &[u8] = include_bytes!("font.psf");
+
+/// The glyph drawn for characters the font has none for.
+pub const MISSING_GLYPH: u8 = 0x7f;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE512: u8 = 0x01;
+const PSF2_MAGIC: u32 = 0x864a_b572;
+
+/// The 16 VGA colors, as 0xrrggbb, so text keeps the colors it has on the
+/// text screen.
+const PALETTE: [u32; 16] = [
+    0x00_00_00, 0x00_00_aa, 0x00_aa_00, 0x00_aa_aa,
+    0xaa_00_00, 0xaa_00_aa, 0xaa_55_00, 0xaa_aa_aa,
+    0x55_55_55, 0x55_55_ff, 0x55_ff_55, 0x55_ff_ff,
+    0xff_55_55, 0xff_55_ff, 0xff_ff_55, 0xff_ff_ff,
+];
+
+/// A PSF font, version 1 or 2.
+pub struct Font {
+    glyphs: &'static [u8],
+    count: usize,
+    width: usize,
+    height: usize,
+    /// The bytes in one glyph.
+    size: usize,
+}
+
+impl Font {
+    pub fn parse(data: &'static [u8]) -> Option<Font> {
+        if data.len() >= 4 && data[..2] == PSF1_MAGIC {
+            let count = if data[2] & PSF1_MODE512 != 0 { 512 } else { 256 };
+            let height = data[3] as usize;
+            Font::new(&data[4..], count, 8, height, height)
+        } else if data.len() >= 32 && LittleEndian::read_u32(&data[0..4]) == PSF2_MAGIC {
+            let header_size = LittleEndian::read_u32(&data[8..12]) as usize;
+            let count = LittleEndian::read_u32(&data[16..20]) as usize;
+            let size = LittleEndian::read_u32(&data[20..24]) as usize;
+            let height = LittleEndian::read_u32(&data[24..28]) as usize;
+            let width = LittleEndian::read_u32(&data[28..32]) as usize;
+            Font::new(data.get(header_size..)?, count, width, height, size)
+        } else {
+            None
+        }
+    }
+
+    fn new(glyphs: &'static [u8], count: usize, width: usize, height: usize, size: usize) -> Option<Font> {
+        if width == 0 || height == 0 || size < (width + 7) / 8 * height || glyphs.len() < count * size {
+            return None;
+        }
+        Some(Font { glyphs, count, width, height, size })
+    }
+
+    /// Whether pixel `x`, `y` of glyph `index` is set.
+    fn pixel(&self, index: usize, x: usize, y: usize) -> bool {
+        let index = if index < self.count { index } else { MISSING_GLYPH as usize };
+        let row = index * self.size + y * ((self.width + 7) / 8);
+        self.glyphs[row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// Whether the framebuffer the boot loader describes is one we can draw
+/// on: direct RGB color, 2 to 4 bytes a pixel.
+pub fn usable(tag: &FramebufferTag) -> bool {
+    match tag.buffer_type {
+        FramebufferType::RGB { .. } => tag.bpp == 16 || tag.bpp == 24 || tag.bpp == 32,
+        _ => false,
+    }
+}
+
+pub struct Framebuffer {
+    buffer: &'static mut [u8],
+    pitch: usize,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    /// The pixel values for the 16 VGA colors.
+    palette: [u32; 16],
+    font: Font,
+}
+
+impl Framebuffer {
+    /// The framebuffer the boot loader set up, if it set up one we can
+    /// use. memory::init has mapped it at FRAMEBUFFER_START.
+    pub fn new(boot_info: &BootInformation) -> Option<Framebuffer> {
+        let tag = boot_info.framebuffer_tag().filter(usable)?;
+        let font = Font::parse(FONT).expect("bad console font");
+        let (red, green, blue) = match tag.buffer_type {
+            FramebufferType::RGB { red, green, blue } => (red, green, blue),
+            _ => return None,
+        };
+
+        let mut palette = [0; 16];
+        for (value, &rgb) in palette.iter_mut().zip(PALETTE.iter()) {
+            let channel = |c: u32, position: u8, size: u8| (c >> (8 - size.min(8))) << position;
+            *value = channel(rgb >> 16 & 0xff, red.position, red.size) |
+                channel(rgb >> 8 & 0xff, green.position, green.size) |
+                channel(rgb & 0xff, blue.position, blue.size);
+        }
+
+        let pitch = tag.pitch as usize;
+        let height = tag.height as usize;
+        let offset = (tag.address % 4096) as usize;
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut((FRAMEBUFFER_START + offset) as *mut u8, pitch * height)
+        };
+        Some(Framebuffer {
+            buffer,
+            pitch,
+            width: tag.width as usize,
+            height,
+            bytes_per_pixel: tag.bpp as usize / 8,
+            palette,
+            font,
+        })
+    }
+
+    /// The number of columns and rows of character cells that fit.
+    pub fn cells(&self) -> (usize, usize) {
+        (self.width / self.font.width, self.height / self.font.height)
+    }
+
+    /// Draws glyph `glyph` in the cell at `row`, `col` in VGA colors
+    /// `foreground` on `background`.
+    pub fn draw_cell(&mut self, row: usize, col: usize, glyph: u8, foreground: u8, background: u8) {
+        let foreground = self.palette[foreground as usize & 0xf];
+        let background = self.palette[background as usize & 0xf];
+        let (top, left) = (row * self.font.height, col * self.font.width);
+        for y in 0..self.font.height {
+            for x in 0..self.font.width {
+                let value = if self.font.pixel(glyph as usize, x, y) { foreground } else { background };
+                self.put_pixel(left + x, top + y, value);
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, value: u32) {
+        let offset = y * self.pitch + x * self.bytes_per_pixel;
+        let pixel = &mut self.buffer[offset..offset + self.bytes_per_pixel];
+        match pixel.len() {
+            4 => LittleEndian::write_u32(pixel, value),
+            len => LittleEndian::write_uint(pixel, u64::from(value), len),
+        }
+    }
+
+    /// Moves the text up a row of cells, leaving the last row as it was.
+    pub fn scroll(&mut self) {
+        let row_bytes = self.font.height * self.pitch;
+        let rows = self.cells().1;
+        self.buffer.copy_within(row_bytes..rows * row_bytes, 0);
+    }
+}
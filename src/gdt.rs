@@ -6,24 +6,47 @@ use alloc::boxed::Box;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// SYSCALL and SYSRET find the segments by their position relative to each
+/// other, so the order below must not change: kernel code, kernel data,
+/// user data, user code.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
+/// A present, writable data segment. `Descriptor` has no constructor for a
+/// kernel data segment, so the bits are spelled out.
+const KERNEL_DATA_SEGMENT: u64 = (1 << 41) | (1 << 44) | (1 << 47);
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
+        (gdt, Selectors { code_selector, data_selector, user_data_selector, user_code_selector, tss_selector })
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+/// Mutable so the ring 0 stack can follow the running thread.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+pub fn init() {
+    use x86_64::instructions::segmentation::{set_cs, load_ss};
+    use x86_64::instructions::tables::load_tss;
+
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096;
             let stack = Box::<[u8; STACK_SIZE]>::new([8; STACK_SIZE]);
 
@@ -31,17 +54,23 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss
-    };
-}
-
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
+    }
 
     GDT.0.load();
+    assert_eq!(GDT.1.code_selector.0, KERNEL_CODE_SELECTOR);
+    assert_eq!(GDT.1.data_selector.0, KERNEL_DATA_SELECTOR);
+    assert_eq!(GDT.1.user_data_selector.0, USER_DATA_SELECTOR);
+    assert_eq!(GDT.1.user_code_selector.0, USER_CODE_SELECTOR);
     unsafe {
         set_cs(GDT.1.code_selector);
+        load_ss(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in ring 3.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe {
+        TSS.privilege_stack_table[0] = top;
+    }
+}
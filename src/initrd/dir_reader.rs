@@ -28,16 +28,9 @@ impl Iterator for Reader<'_> {
             Some(d) => {
                 self.pos += 1;
                 let h = d.header();
-                let name = "/".to_owned() + &h.name;
-                let atime = match h.atime {
-                    Some(t) => t as u32,
-                    None => 0
-                };
+                let name = "/".to_owned() + h.name.trim_end_matches('/');
                 let qid = self.qid_pool.put(&name, super::tar_to_qid(h.typeflag.clone())).to_owned();
-                Some(nine_p::dir::Dir::new(0, 0, &qid, 0, atime,
-                                           h.mtime as u32, d.data().len() as u64,
-                                           &name, h.uname.as_str(), h.gname.as_str(),
-                                           h.uname.as_str()))
+                Some(super::tar_to_dir(&qid, d))
             }
             None => None
         }
@@ -20,6 +20,26 @@ fn tar_to_qid(tar_type: tar::headers::FileType) -> nine_p::qidpool::QidType {
     }
 }
 
+fn tar_to_dir(qid: &nine_p::qidpool::Qid, entry: &tar::TarEntry) -> nine_p::dir::Dir {
+    let h = entry.header();
+    let name = h.name.trim_end_matches('/');
+    let name = match name.rfind('/') {
+        Some(i) => &name[i + 1..],
+        None => name
+    };
+    let mut mode = (h.mode & 0o777) as u32;
+    if qid.qid_type().contains(nine_p::qidpool::QidType::DIRECTORY) {
+        mode |= nine_p::dir::DMDIR;
+    }
+    let atime = match h.atime {
+        Some(t) => t as u32,
+        None => h.mtime as u32
+    };
+    nine_p::dir::Dir::new(0, 0, qid, mode, atime, h.mtime as u32,
+                          entry.data().len() as u64, name,
+                          h.uname.as_str(), h.gname.as_str(), h.uname.as_str())
+}
+
 #[derive(Debug)]
 pub struct InitRD<'a> {
     headers: Option<Vec<tar::TarEntry<'a>>>,
@@ -53,31 +73,22 @@ impl<'a> InitRD<'a> {
     }
 
     fn list_dir(&mut self, path: &str) -> Vec<tar::TarEntry<'a>> {
-        if path == "/" {
-            self.headers().into_iter()
-                .filter(|entry| !entry.header().name.contains("/"))
-                .collect()
-        } else {
-            self.headers().into_iter().filter(|entry| {
-                let h = entry.header();
-                let name = &h.name;
-                if !name.starts_with(path) {
-                    return false;
-                } else {
-                    let after: Vec<_> = name.split(path).collect();
-                    let after = after[1..].into_iter().fold(String::new(), |a, b| a + path + b);
-                    return !after.contains("/");
-                }
-            }).collect()
-        }
+        let path = path.trim_end_matches('/');
+        self.headers().into_iter().filter(|entry| {
+            let name = entry.header().name.trim_end_matches('/');
+            match name.rfind('/') {
+                Some(i) => path.len() == i + 1 && path[1..] == name[..i],
+                None => path.is_empty()
+            }
+        }).collect()
     }
 
     pub fn stat(&mut self, path: &str) -> Option<tar::TarEntry<'a>> {
         if path == "/" {
-            unimplemented!();
+            None
         } else {
             let file: Vec<tar::TarEntry> = self.headers().into_iter().filter(|entry| {
-                let name = "/".to_owned() + &entry.header().name;
+                let name = "/".to_owned() + entry.header().name.trim_end_matches('/');
                 name.as_str() == path
             }).collect();
             match file.len() {
@@ -199,6 +210,7 @@ impl<'a> nine_p::NinePServer for InitRDServer<'a> {
             let entry = self.init_rd.stat(&file.name()).unwrap();
             file.set_rwc(Box::new(entry.data()));
         }
+        file.set_access(mode.access());
 
         Ok((qid, 0))
     }
@@ -225,14 +237,17 @@ impl<'a> nine_p::NinePServer for InitRDServer<'a> {
         };
 
         fn handle_element(path: &str, name: &str) -> String {
+            let path = path.trim_end_matches('/');
             if name != ".." {
                 let mut path = path.to_string();
                 path.push_str("/");
                 path.push_str(name);
-                path.to_string()
+                path
             } else {
-                let elems: Vec<_> = path.split('/').collect();
-                elems[..elems.len() - 1].into_iter().fold(String::new(), |a, b| a + "/" + b)
+                match path.rfind('/') {
+                    Some(0) | None => "/".to_string(),
+                    Some(i) => path[..i].to_string()
+                }
             }
         }
 
@@ -246,13 +261,17 @@ impl<'a> nine_p::NinePServer for InitRDServer<'a> {
                 for name in names {
                     match out_qid.last() {
                         Some(q) => {
-                            if q.qid_type().contains(nine_p::qidpool::QidType::DIRECTORY) {
+                            if !q.qid_type().contains(nine_p::qidpool::QidType::DIRECTORY) {
                                 return Ok(out_qid);
                             }
                         }
                         None => {}
                     }
                     path = handle_element(&path, name);
+                    if path == "/" {
+                        out_qid.push(self.qid_pool.put("/", nine_p::qidpool::QidType::DIRECTORY));
+                        continue;
+                    }
                     match self.init_rd.stat(&path) {
                         Some(e) => {
                             let qid = self.qid_pool.put(&path, tar_to_qid(e.header().typeflag.clone())).to_owned();
@@ -283,7 +302,20 @@ impl<'a> nine_p::NinePServer for InitRDServer<'a> {
         Err(nine_p::DevError::PermissionDenied)
     }
 
-    fn stat(&self) -> nine_p::Result<()> {
-        unimplemented!()
+    fn stat(&mut self, fid: nine_p::Fid) -> nine_p::Result<nine_p::dir::Dir> {
+        self.check_fid(fid)?;
+
+        let path = self.files.get(&fid).unwrap().name();
+        let qid = self.qid_pool.get(&path).unwrap();
+
+        if path == "/" {
+            return Ok(nine_p::dir::Dir::new(0, 0, &qid, nine_p::dir::DMDIR | 0o555, 0, 0, 0,
+                                            "/", "", "", ""));
+        }
+
+        match self.init_rd.stat(&path) {
+            Some(entry) => Ok(tar_to_dir(&qid, &entry)),
+            None => Err(nine_p::DevError::NoSuchFile)
+        }
     }
 }
\ No newline at end of file
@@ -1,8 +1,15 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, layouts};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::instructions::interrupts;
+use alloc::format;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use core::{fmt, mem};
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, KeyCode, KeyState, layouts};
 use spin::Mutex;
 use lazy_static::lazy_static;
-use crate::{println, print, gdt, hlt_loop};
+use crate::{klog, gdt, clock, cons, sched, proc, syscall, uart, vga, USER_START, USER_END};
 use pic8259_simple::ChainedPics;
 use spin;
 
@@ -14,6 +21,7 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Com1 = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -29,52 +37,252 @@ impl InterruptIndex {
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+}
+
+/// Whether a shift key is held, which the keyboard decoder keeps to itself.
+static SHIFT: AtomicBool = AtomicBool::new(false);
+
+/// Unmasks the interrupt at the PIC. The PICs come up with whatever masks
+/// the firmware left, which needn't include every device we drive.
+pub fn enable_irq(index: InterruptIndex) {
+    use x86_64::instructions::port::Port;
+
+    let irq = index.as_u8() - PIC_1_OFFSET;
+    let (mut mask, bit): (Port<u8>, u8) = if irq < 8 {
+        (Port::new(0x21), irq)
+    } else {
+        (Port::new(0xa1), irq - 8)
+    };
+    interrupts::without_interrupts(|| unsafe {
+        let m = mask.read();
+        mask.write(m & !(1 << bit));
+    });
+}
+
+extern "C" {
+    fn trap_divide_error();
+    fn trap_debug();
+    fn trap_nmi();
+    fn trap_breakpoint();
+    fn trap_overflow();
+    fn trap_bound_range();
+    fn trap_invalid_opcode();
+    fn trap_device_not_available();
+    fn trap_double_fault();
+    fn trap_coprocessor_overrun();
+    fn trap_invalid_tss();
+    fn trap_segment_not_present();
+    fn trap_stack_segment();
+    fn trap_general_protection();
+    fn trap_page_fault();
+    fn trap_x87_floating_point();
+    fn trap_alignment_check();
+    fn trap_machine_check();
+    fn trap_simd_floating_point();
+    fn trap_virtualization();
+    fn trap_security();
+}
+
+/// An entry stub from trap.s as the handler type an IDT entry wants. The
+/// stubs save the registers themselves, so the type is only for show.
+macro_rules! stub {
+    ($stub:ident) => {
+        unsafe { mem::transmute($stub as unsafe extern "C" fn()) }
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.divide_error.set_handler_fn(stub!(trap_divide_error));
+        idt.debug.set_handler_fn(stub!(trap_debug));
+        idt.non_maskable_interrupt.set_handler_fn(stub!(trap_nmi));
+        idt.breakpoint.set_handler_fn(stub!(trap_breakpoint));
+        idt.overflow.set_handler_fn(stub!(trap_overflow));
+        idt.bound_range_exceeded.set_handler_fn(stub!(trap_bound_range));
+        idt.invalid_opcode.set_handler_fn(stub!(trap_invalid_opcode));
+        idt.device_not_available.set_handler_fn(stub!(trap_device_not_available));
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
+            idt.double_fault.set_handler_fn(stub!(trap_double_fault))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        idt.coprocessor_segment_overrun.set_handler_fn(stub!(trap_coprocessor_overrun));
+        idt.invalid_tss.set_handler_fn(stub!(trap_invalid_tss));
+        idt.segment_not_present.set_handler_fn(stub!(trap_segment_not_present));
+        idt.stack_segment_fault.set_handler_fn(stub!(trap_stack_segment));
+        idt.general_protection_fault.set_handler_fn(stub!(trap_general_protection));
+        idt.page_fault.set_handler_fn(stub!(trap_page_fault));
+        idt.x87_floating_point.set_handler_fn(stub!(trap_x87_floating_point));
+        idt.alignment_check.set_handler_fn(stub!(trap_alignment_check));
+        idt.machine_check.set_handler_fn(stub!(trap_machine_check));
+        idt.simd_floating_point.set_handler_fn(stub!(trap_simd_floating_point));
+        idt.virtualization.set_handler_fn(stub!(trap_virtualization));
+        idt.security_exception.set_handler_fn(stub!(trap_security));
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
 
         idt
     };
 }
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+pub fn init_idt() {
+    IDT.load();
+}
+
+const DEBUG: u64 = 1;
+const BREAKPOINT: u64 = 3;
+const PAGE_FAULT: u64 = 14;
+
+/// The registers saved on entry to an exception, lowest address first. A
+/// note handler is given a copy of them, as in Plan 9.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Ureg {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// The error code the CPU pushed, or 0 for exceptions without one.
+    pub error: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Ureg {
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
     }
+}
 
+impl fmt::Display for Ureg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rax {:016x} rbx {:016x} rcx {:016x}\n", self.rax, self.rbx, self.rcx)?;
+        write!(f, "rdx {:016x} rsi {:016x} rdi {:016x}\n", self.rdx, self.rsi, self.rdi)?;
+        write!(f, "rbp {:016x} rsp {:016x} r8  {:016x}\n", self.rbp, self.rsp, self.r8)?;
+        write!(f, "r9  {:016x} r10 {:016x} r11 {:016x}\n", self.r9, self.r10, self.r11)?;
+        write!(f, "r12 {:016x} r13 {:016x} r14 {:016x}\n", self.r12, self.r13, self.r14)?;
+        write!(f, "r15 {:016x} rip {:016x} flags {:016x}\n", self.r15, self.rip, self.rflags)?;
+        write!(f, "cs {:04x} ss {:04x} vector {} error {:#x} cr2 {:016x} cr3 {:016x}",
+               self.cs, self.ss, self.vector, self.error,
+               Cr2::read().as_u64(), Cr3::read().0.start_address().as_u64())
+    }
+}
 
-pub fn init_idt() {
-    IDT.load();
+/// The exception names Plan 9 uses in its trap notes.
+fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug exception",
+        2 => "nonmaskable interrupt",
+        3 => "breakpoint",
+        4 => "overflow",
+        5 => "bounds check",
+        6 => "invalid opcode",
+        7 => "coprocessor not available",
+        8 => "double fault",
+        9 => "coprocessor segment overrun",
+        10 => "invalid TSS",
+        11 => "segment not present",
+        12 => "stack exception",
+        13 => "general protection violation",
+        14 => "page fault",
+        16 => "coprocessor error",
+        17 => "alignment check",
+        18 => "machine check",
+        19 => "simd error",
+        20 => "virtualization exception",
+        30 => "security exception",
+        _ => "reserved"
+    }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Called by the entry stubs in trap.s for every exception. Those in user
+/// mode become notes to the process, which its note handler may catch;
+/// those in the kernel are fatal.
+#[no_mangle]
+pub extern "C" fn trap_handler(ureg: &mut Ureg) {
+    match ureg.vector {
+        PAGE_FAULT => page_fault(ureg),
+        DEBUG | BREAKPOINT if !ureg.from_user() => {
+            klog!(Warn, "EXCEPTION: {}\n{}", exception_name(ureg.vector), ureg);
+        }
+        BREAKPOINT => post_trap_note(ureg, "sys: breakpoint".to_owned()),
+        vector if ureg.from_user() => {
+            let note = format!("sys: trap: {} pc={:#x}", exception_name(vector), ureg.rip);
+            post_trap_note(ureg, note);
+        }
+        vector => panic!("EXCEPTION: {} in kernel\n{}", exception_name(vector), ureg)
+    }
+
+    if ureg.from_user() {
+        syscall::deliver_note(ureg);
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
-    println!("EXCEPTION: PAGE FAULT\n{:#?}\n{:#?}", error_code, stack_frame);
-    hlt_loop();
+fn post_trap_note(ureg: &Ureg, note: String) {
+    if sched::current_proc().is_none() {
+        panic!("{} with no process\n{}", note, ureg);
+    }
+    proc::current().write().post_note(&note, true);
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, _error_code: u64) {
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    hlt_loop();
+fn page_fault(ureg: &mut Ureg) {
+    let addr = Cr2::read();
+    let error = PageFaultErrorCode::from_bits_truncate(ureg.error);
+    let write = error.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let fetch = error.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+    let user_addr = addr.as_u64() >= USER_START as u64 && addr.as_u64() < USER_END as u64;
+    if user_addr {
+        // the space mustn't still be held if the process exits below
+        let resolved = match sched::address_space() {
+            Some(space) => space.fault(addr, write, fetch),
+            None => false
+        };
+        if resolved {
+            return;
+        }
+    }
+
+    let note = format!("sys: trap: fault {} addr={:#x} pc={:#x}",
+                       if write { "write" } else { "read" }, addr.as_u64(), ureg.rip);
+    if ureg.from_user() {
+        post_trap_note(ureg, note);
+    } else if user_addr && sched::current_proc().is_some() {
+        // the kernel touching bad user memory on the process's behalf;
+        // system calls do that with no locks held, so nothing is left locked
+        proc::exits(&note);
+    } else {
+        panic!("EXCEPTION: PAGE FAULT at {:#x} in kernel\n{:?}\n{}", addr.as_u64(), error, ureg);
+    }
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-//    print!(".");
+    // acknowledge first, the scheduler may switch away before we return
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    clock::tick();
+    sched::tick();
 }
 
 
@@ -86,10 +294,32 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
 
     let scancode: u8 = unsafe { port.read() };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+        let down = key_event.state == KeyState::Down;
+        if key_event.code == KeyCode::ShiftLeft || key_event.code == KeyCode::ShiftRight {
+            SHIFT.store(down, Ordering::Relaxed);
+        }
+        // Shift+PgUp and Shift+PgDn look through the screen's scrollback
+        // rather than being input
+        let scroll = SHIFT.load(Ordering::Relaxed) &&
+            (key_event.code == KeyCode::PageUp || key_event.code == KeyCode::PageDown);
+        if scroll {
+            if down {
+                let mut writer = vga::WRITER.lock();
+                if key_event.code == KeyCode::PageUp {
+                    writer.scroll_back();
+                } else {
+                    writer.scroll_forward();
+                }
+            }
+        } else {
+            match keyboard.process_keyevent(key_event) {
+                Some(DecodedKey::Unicode(character)) => cons::kbd_putc(character),
+                Some(DecodedKey::RawKey(code)) => {
+                    if let Some(sequence) = key_sequence(code) {
+                        sequence.chars().for_each(cons::kbd_putc);
+                    }
+                }
+                None => {}
             }
         }
     }
@@ -97,4 +327,28 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
-}
\ No newline at end of file
+}
+
+/// The input a key with no character sends, as a VT100's does.
+fn key_sequence(code: KeyCode) -> Option<&'static str> {
+    match code {
+        KeyCode::ArrowUp => Some("\x1b[A"),
+        KeyCode::ArrowDown => Some("\x1b[B"),
+        KeyCode::ArrowRight => Some("\x1b[C"),
+        KeyCode::ArrowLeft => Some("\x1b[D"),
+        KeyCode::Home => Some("\x1b[H"),
+        KeyCode::End => Some("\x1b[F"),
+        KeyCode::Insert => Some("\x1b[2~"),
+        KeyCode::PageUp => Some("\x1b[5~"),
+        KeyCode::PageDown => Some("\x1b[6~"),
+        _ => None,
+    }
+}
+
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    uart::interrupt();
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+    }
+}
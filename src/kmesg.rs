@@ -0,0 +1,139 @@
+//! The kernel's log: a ring holding the most recent text printed, each
+//! line stamped with the time since boot and a severity. The console
+//! serves it as `kmesg`.
+
+use alloc::string::String;
+use core::fmt;
+use spin::Mutex;
+use crate::clock;
+
+/// How much of the log is kept, as in Plan 9.
+const KMESG_SIZE: usize = 16 * 1024;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+struct KMesg {
+    buf: [u8; KMESG_SIZE],
+    start: usize,
+    len: usize,
+    /// Whether the next text starts a line, and so needs a stamp.
+    line_start: bool,
+    /// The level the current line was started with.
+    level: Level,
+}
+
+impl KMesg {
+    const fn new() -> KMesg {
+        KMesg {
+            buf: [0; KMESG_SIZE],
+            start: 0,
+            len: 0,
+            line_start: true,
+            level: Level::Info,
+        }
+    }
+
+    /// Appends a byte, dropping the oldest if the ring is full.
+    fn push(&mut self, byte: u8) {
+        self.buf[(self.start + self.len) % KMESG_SIZE] = byte;
+        if self.len < KMESG_SIZE {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % KMESG_SIZE;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|b| self.push(b));
+    }
+
+    /// Whether text has been dropped to make room, so the oldest line
+    /// left is likely cut short.
+    fn wrapped(&self) -> bool {
+        self.len == KMESG_SIZE
+    }
+}
+
+impl fmt::Write for KMesg {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.line_start {
+                let ms = clock::uptime_ms();
+                let level = self.level;
+                fmt::write(&mut Stamp(self), format_args!("[{:>5}.{:03}] {}: ", ms / 1000, ms % 1000, level.name()))?;
+            }
+            self.push(byte);
+            self.line_start = byte == b'\n';
+        }
+        Ok(())
+    }
+}
+
+/// Writes straight into the ring, for the stamp itself.
+struct Stamp<'a>(&'a mut KMesg);
+
+impl fmt::Write for Stamp<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_str(s);
+        Ok(())
+    }
+}
+
+static KMESG: Mutex<KMesg> = Mutex::new(KMesg::new());
+
+/// Adds printed text to the log at `level`. A line keeps the level it was
+/// started with. Called with interrupts off.
+pub fn log(level: Level, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut kmesg = KMESG.lock();
+    if kmesg.line_start {
+        kmesg.level = level;
+    }
+    let _ = kmesg.write_fmt(args);
+}
+
+/// The log, oldest line first.
+pub fn text() -> String {
+    let bytes = x86_64::instructions::interrupts::without_interrupts(|| {
+        let kmesg = KMESG.lock();
+        let mut bytes = alloc::vec::Vec::with_capacity(kmesg.len);
+        for i in 0..kmesg.len {
+            bytes.push(kmesg.buf[(kmesg.start + i) % KMESG_SIZE]);
+        }
+        if kmesg.wrapped() {
+            // start at the first whole line
+            let first = bytes.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+            bytes.drain(..first);
+        }
+        bytes
+    });
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Prints a line at the given level, one of `Error`, `Warn`, `Info` or
+/// `Debug`. Plain `println!` logs at `Info`.
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $($arg:tt)*) => (
+        $crate::vga::_log($crate::kmesg::Level::$level, format_args!("{}\n", format_args!($($arg)*)))
+    );
+}
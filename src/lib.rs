@@ -20,18 +20,32 @@ extern crate bitflags;
 extern crate byteorder;
 
 pub mod vga;
+pub mod framebuffer;
 pub mod interrupts;
 pub mod memory;
 pub mod gdt;
 pub mod tar;
 pub mod initrd;
+pub mod ramfs;
 pub mod nine_p;
 pub mod dev;
 pub mod namespace;
+pub mod proc;
+pub mod clock;
+pub mod sched;
+pub mod syscall;
+pub mod mnt;
+pub mod elf;
+pub mod exec;
+pub mod cons;
+pub mod uart;
+pub mod kmesg;
 
 use core::panic::PanicInfo;
 use memory::heap_allocator::Allocator;
 use alloc::boxed::Box;
+use alloc::borrow::ToOwned;
+use alloc::vec;
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -42,24 +56,43 @@ pub fn hlt_loop() -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     x86_64::instructions::interrupts::disable();
-    println!("{}", info);
+    klog!(Error, "{}", info);
     hlt_loop();
 }
 
 #[alloc_error_handler]
 fn alloc_error(info: core::alloc::Layout) -> ! {
     x86_64::instructions::interrupts::disable();
-    println!("Allocation failed: {:?}", info);
+    klog!(Error, "Allocation failed: {:?}", info);
     hlt_loop();
 }
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
 pub const INITRD_START: usize = 0o_000_002_000_000_0000;
+pub const KSTACK_START: usize = 0o_000_003_000_000_0000;
+pub const KSTACK_END: usize = 0o_000_004_000_000_0000;
+pub const TEMPORARY_PAGE: usize = 0o_000_004_000_000_0000;
+pub const FRAMEBUFFER_START: usize = 0o_000_005_000_000_0000;
+pub const USER_START: usize = 0o_001_000_000_000_0000;
+pub const USER_END: usize = 0o_400_000_000_000_0000;
 
 #[global_allocator]
 static HEAP_ALLOCATOR: Allocator = Allocator::empty();
 
+/// The kernel heap's size and the state of its hole list.
+pub fn heap_stats() -> (usize, memory::heap_allocator::HoleStats) {
+    HEAP_ALLOCATOR.heap_stats()
+}
+
+/// Hit and miss counts for each of the heap's slab caches.
+pub fn slab_stats() -> [memory::slab::SlabStats; memory::slab::NUM_CLASSES] {
+    HEAP_ALLOCATOR.slab_stats()
+}
+
+static mut BOOT_INFO: Option<multiboot2::BootInformation> = None;
+
 fn enable_nxe_bit() {
     use x86_64::registers::model_specific::{Efer, EferFlags};
 
@@ -81,16 +114,30 @@ fn enable_write_protect_bit() {
 }
 
 pub fn init<'a>(multiboot_information_p: usize) -> initrd::InitRD<'a> {
+    uart::init();
     vga::WRITER.lock().clear_screen();
     println!("Starting planRust");
-    let boot_info = unsafe { multiboot2::load(multiboot_information_p) };
+    let boot_info = unsafe {
+        BOOT_INFO = Some(multiboot2::load(multiboot_information_p));
+        BOOT_INFO.as_ref().unwrap()
+    };
     let init_rd = memory::init(boot_info);
     unsafe {
-        HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_START + HEAP_SIZE);
+        HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    if let Some(framebuffer) = framebuffer::Framebuffer::new(boot_info) {
+        // nothing printed so far showed, so start with the log
+        let mut writer = vga::WRITER.lock();
+        writer.use_framebuffer(framebuffer);
+        writer.write_string(&kmesg::text());
     }
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::enable_irq(interrupts::InterruptIndex::Com1);
+    clock::init();
+    sched::init();
     x86_64::instructions::interrupts::enable();
     init_rd
 }
@@ -113,28 +160,23 @@ pub extern "C" fn rust_start(multiboot_information_p: usize) -> ! {
     let init_rd_server = initrd::InitRDServer::new('/', "initrd", init_rd);
 
     dev::insert_dev_driver(Box::new(init_rd_server));
+    dev::insert_dev_driver(Box::new(ramfs::RamFSServer::new('R', "ramfs")));
+    dev::insert_dev_driver(Box::new(mnt::MntServer::new()));
+    dev::insert_dev_driver(Box::new(cons::ConsServer::new()));
+    dev::insert_dev_driver(Box::new(uart::UartServer::new()));
 
     let mut root_namespace = namespace::Namespace::new();
 
-    root_namespace.bind("/", "#/");
-
-    let root = root_namespace.open_file("#/").unwrap();
-
-    let root_fid = root.0.fid_pool().get_fid();
-    println!("{:?}", root.0.server().lock().walk(root.1, root_fid, &[]));
-    println!("{:?}", root.0.server().lock().open(root_fid, &nine_p::FileMode::new(nine_p::FileAccessMode::Read, false, false)));
-    println!("{:?}", root.0.server().lock().read(root_fid, 0, 1000));
-    println!("{:?}", root.0.server().lock().clunk(root_fid));
-    root.0.fid_pool().clunk_fid(root_fid);
-    drop(root_fid);
-    let test_fid = root.0.fid_pool().get_fid();
-    println!("{:?}", root.0.server().lock().walk(root.1, test_fid, &["test"]));
-    println!("{:?}", root.0.server().lock().open(test_fid, &nine_p::FileMode::new(nine_p::FileAccessMode::Read, false, false)));
-    println!("{:?}", root.0.server().lock().read(test_fid, 0, 1000));
-    println!("{:?}", root.0.server().lock().clunk(test_fid));
-    root.0.fid_pool().clunk_fid(test_fid);
-    drop(test_fid);
-
-    println!("It did not crash");
-    hlt_loop();
+    root_namespace.bind("/", "#/", namespace::BindFlags::MREPL);
+    root_namespace.bind("/tmp", "#R", namespace::BindFlags::MREPL | namespace::BindFlags::MCREATE);
+    root_namespace.bind("/dev", "#c", namespace::BindFlags::MREPL);
+
+    let pid = proc::init(root_namespace);
+    sched::spawn(Some(pid), || {
+        let err = exec::exec("/bin/init".to_owned(), vec!["init".to_owned()]);
+        panic!("exec /bin/init: {}", err.description());
+    });
+
+    sched::set_proc(None);
+    sched::exit();
 }
\ No newline at end of file
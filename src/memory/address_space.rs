@@ -0,0 +1,270 @@
+//! User address spaces: a page table of their own that shares the kernel's
+//! mappings, and the segments mapped in it.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::vec;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::structures::paging::page::PageRangeInclusive;
+use super::{ForkPage, with_controller};
+
+/// The furthest the stack segment grows down from its top.
+pub const MAX_STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Text,
+    Data,
+    Bss,
+    Stack,
+}
+
+/// The pages from `start` up to `end`, mapped with `flags`.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Segment {
+    pub fn pages(&self) -> PageRangeInclusive {
+        Page::range_inclusive(Page::containing_address(self.start), Page::containing_address(self.end - 1u64))
+    }
+}
+
+/// What the spaces made from one another by `share` have in common: every
+/// segment but the stack, and the page tables those are mapped in.
+#[derive(Debug)]
+struct Shared {
+    segments: Vec<Segment>,
+    tables: Vec<PhysFrame>,
+}
+
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+    shared: Arc<Mutex<Shared>>,
+    stack: Mutex<Option<Segment>>,
+}
+
+impl AddressSpace {
+    /// Makes an address space with nothing but the kernel in it.
+    pub fn new() -> Option<AddressSpace> {
+        let p4_frame = with_controller(|mc| mc.new_user_table())?;
+        Some(AddressSpace {
+            p4_frame,
+            shared: Arc::new(Mutex::new(Shared { segments: Vec::new(), tables: vec![p4_frame] })),
+            stack: Mutex::new(None),
+        })
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments = self.shared.lock().segments.clone();
+        segments.extend(self.stack.lock().clone());
+        segments
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    fn push_segment(&self, segment: Segment) {
+        if segment.kind == SegmentKind::Stack {
+            *self.stack.lock() = Some(segment);
+        } else {
+            self.shared.lock().segments.push(segment);
+        }
+    }
+
+    /// Maps zeroed memory from `start` to `end` and copies `data` in at
+    /// `start`. The space must be the one loaded.
+    pub fn map_segment(&self, kind: SegmentKind, start: VirtAddr, end: VirtAddr, flags: PageTableFlags,
+                       data: &[u8]) -> Option<()> {
+        assert!(self.is_active(), "mapping a segment in an inactive address space");
+        assert!(data.len() as u64 <= end - start, "segment data larger than the segment");
+
+        let segment = Segment { kind, start, end, flags };
+        let pages = segment.pages();
+        with_controller(|mc| mc.map_user_pages(pages.start, pages.end))?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), start.as_mut_ptr::<u8>(), data.len());
+        }
+        with_controller(|mc| mc.set_user_flags(pages.start, pages.end, flags));
+        self.push_segment(segment);
+        Some(())
+    }
+
+    /// Adds a segment whose pages are mapped zeroed when first touched.
+    pub fn add_segment(&self, kind: SegmentKind, start: VirtAddr, end: VirtAddr, flags: PageTableFlags) {
+        self.push_segment(Segment { kind, start, end, flags });
+    }
+
+    /// Resolves a page fault at `addr`. Untouched pages are mapped zeroed,
+    /// or to the page a space sharing the segment has there, copy-on-write
+    /// pages are copied when written, and a fault below the stack grows it.
+    /// Returns false if the access isn't allowed, in which case the fault
+    /// is the program's. The space must be the one loaded.
+    pub fn fault(&self, addr: VirtAddr, write: bool, fetch: bool) -> bool {
+        assert!(self.is_active(), "fault in an inactive address space");
+
+        let page = Page::containing_address(addr);
+        let shared = self.shared.lock();
+        if let Some(segment) = shared.segments.iter().find(|s| s.start <= addr && addr < s.end) {
+            return with_controller(|mc| mc.resolve_shared_fault(page, segment.flags, write, fetch, &shared.tables));
+        }
+        let flags = {
+            let mut stack = self.stack.lock();
+            let flags = stack.as_ref().filter(|s| s.start <= addr && addr < s.end).map(|s| s.flags);
+            match flags.or_else(|| grow_stack(&mut stack, &shared.segments, addr)) {
+                Some(flags) => flags,
+                None => return false
+            }
+        };
+        drop(shared);
+        with_controller(|mc| mc.resolve_user_fault(page, flags, write, fetch))
+    }
+
+    /// Moves the end of the bss, where the heap grows, to `addr` rounded up
+    /// to a page. The pages gained are mapped zeroed when first touched;
+    /// the pages given up are unmapped, from every space sharing the bss.
+    /// Returns false if `addr` is below the bss or the bss would run into
+    /// another segment. The space must be the one loaded.
+    pub fn brk(&self, addr: VirtAddr) -> bool {
+        assert!(self.is_active(), "brk in an inactive address space");
+
+        let end = addr.align_up(4096u64);
+        let mut shared = self.shared.lock();
+        let Shared { ref mut segments, ref tables } = *shared;
+        let bss = match heap_segment(segments) {
+            Some(bss) => bss,
+            None => return false
+        };
+        let (start, old_end) = (segments[bss].start, segments[bss].end);
+        if end < start {
+            return false;
+        }
+        if end > old_end {
+            let stack = self.stack.lock();
+            let mut others = segments.iter().enumerate().filter(|&(i, _)| i != bss).map(|(_, s)| s).chain(stack.iter());
+            if others.any(|s| s.start < end && s.end > old_end) {
+                return false;
+            }
+        }
+        segments[bss].end = end;
+        if end < old_end {
+            let (first, last) = (Page::containing_address(end), Page::containing_address(old_end - 1u64));
+            with_controller(|mc| {
+                for &table in tables {
+                    if table == self.p4_frame {
+                        mc.unmap_user_pages(first, last);
+                    } else {
+                        mc.unmap_inactive_user_pages(table, first, last);
+                    }
+                }
+            });
+        }
+        true
+    }
+
+    /// Makes a space for a child that doesn't share memory. The child gets
+    /// the same pages, copy-on-write where they are writable, save that
+    /// writable pages this space shares with others are copied outright.
+    /// The space must be the one loaded.
+    pub fn fork(&self) -> Option<AddressSpace> {
+        assert!(self.is_active(), "forking an inactive address space");
+
+        let shared = self.shared.lock();
+        let stack = self.stack.lock().clone();
+        // a copy-on-write page written by another space would change under
+        // the child
+        let shared_how = if shared.tables.len() > 1 { ForkPage::Copy } else { ForkPage::CopyOnWrite };
+        let mut pages = Vec::new();
+        for segment in &shared.segments {
+            let how = if segment.flags.contains(PageTableFlags::WRITABLE) { shared_how } else { ForkPage::CopyOnWrite };
+            pages.extend(segment.pages().map(|page| (page, segment.flags, how)));
+        }
+        for segment in &stack {
+            pages.extend(segment.pages().map(|page| (page, segment.flags, ForkPage::CopyOnWrite)));
+        }
+        let mut mappings = Vec::with_capacity(pages.len());
+        let p4_frame = with_controller(|mc| mc.fork_user_pages(&pages, &mut mappings))?;
+        Some(AddressSpace {
+            p4_frame,
+            shared: Arc::new(Mutex::new(Shared { segments: shared.segments.clone(), tables: vec![p4_frame] })),
+            stack: Mutex::new(stack),
+        })
+    }
+
+    /// Makes a space for a child that shares memory, as rfork's RFMEM asks.
+    /// It shares every segment but the stack, which it gets copy-on-write,
+    /// so that each has a stack of its own to run on. The space must be
+    /// the one loaded.
+    pub fn share(&self) -> Option<AddressSpace> {
+        assert!(self.is_active(), "sharing an inactive address space");
+
+        let mut shared = self.shared.lock();
+        let stack = self.stack.lock().clone();
+        let mut pages = Vec::new();
+        for segment in &shared.segments {
+            pages.extend(segment.pages().map(|page| (page, segment.flags, ForkPage::Share)));
+        }
+        for segment in &stack {
+            pages.extend(segment.pages().map(|page| (page, segment.flags, ForkPage::CopyOnWrite)));
+        }
+        let mut mappings = Vec::with_capacity(pages.len());
+        let p4_frame = with_controller(|mc| mc.fork_user_pages(&pages, &mut mappings))?;
+        shared.tables.push(p4_frame);
+        Some(AddressSpace {
+            p4_frame,
+            shared: self.shared.clone(),
+            stack: Mutex::new(stack),
+        })
+    }
+}
+
+/// The segment brk moves the end of: the bss above the other `segments`,
+/// which don't include the stack. A program with no bss there gets an
+/// empty one after its data.
+fn heap_segment(segments: &mut Vec<Segment>) -> Option<usize> {
+    let top = segments.iter().enumerate().max_by_key(|(_, s)| s.end)?.0;
+    if segments[top].kind == SegmentKind::Bss {
+        return Some(top);
+    }
+    let start = segments[top].end.align_up(4096u64);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    segments.push(Segment { kind: SegmentKind::Bss, start, end: start, flags });
+    Some(segments.len() - 1)
+}
+
+/// Moves the start of the stack segment down to take in `addr`, if that
+/// keeps it within MAX_STACK_SIZE and clear of the `others`. Returns the
+/// stack's flags if it did.
+fn grow_stack(stack: &mut Option<Segment>, others: &[Segment], addr: VirtAddr) -> Option<PageTableFlags> {
+    let start = addr.align_down(4096u64);
+    let stack = stack.as_mut()?;
+    if addr >= stack.start || stack.end - start > MAX_STACK_SIZE {
+        return None;
+    }
+    if others.iter().any(|s| s.end > start && s.start < stack.start) {
+        return None;
+    }
+    stack.start = start;
+    Some(stack.flags)
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let p4_frame = self.p4_frame;
+        self.shared.lock().tables.retain(|&table| table != p4_frame);
+        with_controller(|mc| mc.free_user_table(p4_frame));
+    }
+}
@@ -0,0 +1,160 @@
+use multiboot2::MemoryAreaIter;
+use x86_64::structures::paging::{PhysFrame, FrameAllocator, FrameDeallocator};
+use x86_64::structures::paging::page::Size4KiB;
+use x86_64::PhysAddr;
+use crate::klog;
+
+/// The most physical memory we manage. Frames above it are never used.
+pub const MAX_PHYS_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+
+const FRAME_SIZE: u64 = 4096;
+const MAX_FRAMES: usize = (MAX_PHYS_MEMORY / FRAME_SIZE) as usize;
+
+/// One bit per frame, set if the frame is free. It lives in the kernel's
+/// bss so it needs no memory allocated for it at boot.
+static mut BITMAP: [u64; MAX_FRAMES / 64] = [0; MAX_FRAMES / 64];
+
+/// For each frame, how many more mappings share it than the one that
+/// allocated it.
+static mut SHARES: [u8; MAX_FRAMES] = [0; MAX_FRAMES];
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    shares: &'static mut [u8],
+    total_frames: usize,
+    free_frames: usize,
+    /// No word before this one has a free frame.
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Makes every frame in the memory areas free except those holding the
+    /// kernel, the modules and the multiboot information. Must be called
+    /// only once, since it takes over the bitmap.
+    pub fn new(kernel_start: PhysAddr, kernel_end: PhysAddr,
+               modules_start: PhysAddr, modules_end: PhysAddr,
+               multiboot_start: PhysAddr, multiboot_end: PhysAddr,
+               memory_areas: MemoryAreaIter) -> BitmapFrameAllocator
+    {
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: unsafe { &mut BITMAP[..] },
+            shares: unsafe { &mut SHARES[..] },
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for area in memory_areas {
+            let start = (area.start_address() + FRAME_SIZE - 1) / FRAME_SIZE;
+            let end = area.end_address() / FRAME_SIZE;
+            if end as usize > MAX_FRAMES {
+                klog!(Warn, "ignoring memory above {:#x}", MAX_PHYS_MEMORY);
+            }
+            for frame in start as usize..core::cmp::min(end as usize, MAX_FRAMES) {
+                if allocator.is_used(frame) {
+                    allocator.set_free(frame);
+                    allocator.total_frames += 1;
+                }
+            }
+        }
+
+        let reserved = [
+            (kernel_start, kernel_end),
+            (modules_start, modules_end),
+            (multiboot_start, multiboot_end),
+        ];
+        for &(start, end) in reserved.iter() {
+            let start = PhysFrame::<Size4KiB>::containing_address(start);
+            let end = PhysFrame::<Size4KiB>::containing_address(end);
+            for frame in PhysFrame::range_inclusive(start, end) {
+                let frame = Self::index(frame);
+                if frame < MAX_FRAMES && !allocator.is_used(frame) {
+                    allocator.set_used(frame);
+                }
+            }
+        }
+        allocator
+    }
+
+    fn index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) == 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+        self.free_frames += 1;
+        if frame / 64 < self.next_word {
+            self.next_word = frame / 64;
+        }
+    }
+
+    /// Adds a mapping to those sharing an allocated frame. Returns false if
+    /// the frame can't be shared any further.
+    pub fn share(&mut self, frame: PhysFrame) -> bool {
+        let frame = Self::index(frame);
+        assert!(frame < MAX_FRAMES && self.is_used(frame), "sharing a free frame");
+        if self.shares[frame] == u8::max_value() {
+            return false;
+        }
+        self.shares[frame] += 1;
+        true
+    }
+
+    pub fn is_shared(&self, frame: PhysFrame) -> bool {
+        let frame = Self::index(frame);
+        frame < MAX_FRAMES && self.shares[frame] > 0
+    }
+
+    /// Drops a mapping of a frame, freeing it if it was the last. Returns
+    /// whether the frame was freed.
+    pub fn release(&mut self, frame: PhysFrame) -> bool {
+        let frame = Self::index(frame);
+        assert!(frame < MAX_FRAMES && self.is_used(frame), "freeing a free frame");
+        if self.shares[frame] > 0 {
+            self.shares[frame] -= 1;
+            return false;
+        }
+        self.set_free(frame);
+        true
+    }
+
+    /// The number of frames of usable memory.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// The number of those frames not allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        while self.next_word < self.bitmap.len() {
+            let word = self.bitmap[self.next_word];
+            if word != 0 {
+                let frame = self.next_word * 64 + word.trailing_zeros() as usize;
+                self.set_used(frame);
+                return Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)));
+            }
+            self.next_word += 1;
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.release(frame);
+    }
+}
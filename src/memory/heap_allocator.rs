@@ -1,11 +1,14 @@
 use x86_64::align_up;
 use core::convert::TryInto;
 use alloc::alloc::{Layout, GlobalAlloc, AllocErr};
+use core::cmp;
 use core::mem;
 use core::mem::size_of;
 use core::ptr::NonNull;
 use core::ops::Deref;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::slab::{size_class, SlabCache, SlabStats, NUM_CLASSES};
 
 pub struct Heap {
     bottom: usize,
@@ -76,6 +79,10 @@ impl Heap {
         self.bottom + self.size
     }
 
+    pub fn hole_stats(&self) -> HoleStats {
+        self.holes.stats()
+    }
+
     pub unsafe fn extend(&mut self, by: usize) {
         let top = self.top();
         let layout = Layout::from_size_align(by, 1).unwrap();
@@ -138,6 +145,30 @@ impl HoleList {
     pub fn min_size() -> usize {
         size_of::<usize>() * 2
     }
+
+    pub fn stats(&self) -> HoleStats {
+        let mut stats = HoleStats {
+            holes: 0,
+            free: 0,
+            largest: 0,
+        };
+        let mut hole = self.first.next.as_ref();
+        while let Some(h) = hole {
+            stats.holes += 1;
+            stats.free += h.size;
+            stats.largest = cmp::max(stats.largest, h.size);
+            hole = h.next.as_ref();
+        }
+        stats
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HoleStats {
+    pub holes: usize,
+    /// Bytes free in all the holes together.
+    pub free: usize,
+    pub largest: usize,
 }
 
 pub struct Hole {
@@ -337,11 +368,68 @@ fn move_helper<T>(x: T) -> T {
     x
 }
 
-pub struct Allocator(Mutex<Heap>);
+/// The least the heap grows by at a time.
+const MIN_GROWTH: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Allocates from the heap, growing it if there's no hole big enough.
+pub fn allocate_growing(heap: &mut Heap, layout: Layout) -> Option<NonNull<u8>> {
+    if let Ok(allocation) = heap.allocate_first_fit(layout) {
+        return Some(allocation);
+    }
+
+    // enough for the allocation wherever its alignment puts it
+    let needed = layout.size() + layout.align() + HoleList::min_size();
+    let by = align_up(cmp::max(needed, MIN_GROWTH) as u64, PAGE_SIZE as u64) as usize;
+    if !super::grow_heap(heap.top(), by) {
+        return None;
+    }
+    unsafe { heap.extend(by) };
+    heap.allocate_first_fit(layout).ok()
+}
+
+/// Small allocations come from the slab caches, everything else from the
+/// heap. Slabs are never given back to the heap.
+pub struct Allocator {
+    heap: Mutex<Heap>,
+    slabs: Mutex<[SlabCache; NUM_CLASSES]>,
+}
 
 impl Allocator {
     pub const fn empty() -> Allocator {
-        Allocator(Mutex::new(Heap::empty()))
+        Allocator {
+            heap: Mutex::new(Heap::empty()),
+            slabs: Mutex::new([
+                SlabCache::new(16),
+                SlabCache::new(32),
+                SlabCache::new(64),
+                SlabCache::new(128),
+                SlabCache::new(256),
+                SlabCache::new(512),
+                SlabCache::new(1024),
+                SlabCache::new(2048),
+            ]),
+        }
+    }
+
+    /// The heap's size and the state of its hole list.
+    pub fn heap_stats(&self) -> (usize, HoleStats) {
+        interrupts::without_interrupts(|| {
+            let heap = self.heap.lock();
+            (heap.size(), heap.hole_stats())
+        })
+    }
+
+    pub fn slab_stats(&self) -> [SlabStats; NUM_CLASSES] {
+        interrupts::without_interrupts(|| {
+            let slabs = self.slabs.lock();
+            let mut stats = [slabs[0].stats(); NUM_CLASSES];
+            for (stat, slab) in stats.iter_mut().zip(slabs.iter()) {
+                *stat = slab.stats();
+            }
+            stats
+        })
     }
 }
 
@@ -349,22 +437,31 @@ impl Deref for Allocator {
     type Target = Mutex<Heap>;
 
     fn deref(&self) -> &Mutex<Heap> {
-        &self.0
+        &self.heap
     }
 }
 
+// Interrupts are held off while the heap is locked, otherwise the scheduler
+// could preempt an allocation from the timer interrupt and then allocate
+// itself. The slabs are always locked before the heap.
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0
-            .lock()
-            .allocate_first_fit(layout)
-            .ok()
-            .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+        interrupts::without_interrupts(|| {
+            let allocation = match size_class(&layout) {
+                Some(class) => self.slabs.lock()[class].allocate(&mut self.heap.lock()),
+                None => allocate_growing(&mut self.heap.lock(), layout)
+            };
+            allocation.map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0
-            .lock()
-            .deallocate(NonNull::new_unchecked(ptr), layout)
+        interrupts::without_interrupts(|| {
+            let ptr = NonNull::new_unchecked(ptr);
+            match size_class(&layout) {
+                Some(class) => self.slabs.lock()[class].deallocate(ptr),
+                None => self.heap.lock().deallocate(ptr, layout)
+            }
+        })
     }
-}
\ No newline at end of file
+}
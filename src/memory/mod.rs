@@ -1,22 +1,400 @@
-pub mod area_allocator;
+pub mod bitmap_allocator;
 pub mod paging;
 pub mod temporary_page;
 pub mod heap_allocator;
+pub mod slab;
+pub mod stack_allocator;
+pub mod address_space;
 
-pub use self::area_allocator::AreaFrameAllocator;
+pub use self::bitmap_allocator::BitmapFrameAllocator;
 pub use self::paging::{ActivePageTable, InactivePageTable};
 pub use self::temporary_page::TemporaryPage;
+pub use self::stack_allocator::{Stack, StackAllocator};
+pub use self::address_space::{AddressSpace, Segment, SegmentKind};
 
 use multiboot2::BootInformation;
 use x86_64::{VirtAddr, PhysAddr};
 use x86_64::structures::paging::page::Size4KiB;
-use x86_64::structures::paging::{PhysFrame, Page, PageSize, PageTableFlags, FrameAllocator, Mapper};
+use x86_64::structures::paging::{PhysFrame, Page, PageSize, PageTableFlags, FrameAllocator, FrameDeallocator, Mapper};
+use alloc::vec::Vec;
 use multiboot2::ElfSectionFlags;
 use crate::println;
 use crate::initrd;
+use crate::framebuffer;
 use core::convert::TryInto;
+use x86_64::registers::control::Cr3;
+use spin::Mutex;
 
-pub fn init<'a>(boot_info: BootInformation) -> initrd::InitRD<'a> {
+/// Everything needed to change the kernel's mappings after boot.
+pub struct MemoryController {
+    active_table: ActivePageTable<'static>,
+    frame_allocator: BitmapFrameAllocator,
+    stack_allocator: StackAllocator,
+    temporary_page: TemporaryPage,
+    /// The table set up at boot, which maps only the kernel.
+    kernel_table: PhysFrame,
+    /// Frames mapped into user space, not counting page tables.
+    user_frames: usize,
+}
+
+impl MemoryController {
+    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+        let MemoryController { ref mut active_table, ref mut frame_allocator, ref mut stack_allocator, .. } = *self;
+        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+    }
+
+    /// The total number of physical frames, and how many are free.
+    pub fn frame_counts(&self) -> (usize, usize) {
+        (self.frame_allocator.total_frames(), self.frame_allocator.free_frames())
+    }
+
+    pub fn user_frames(&self) -> usize {
+        self.user_frames
+    }
+
+    /// Makes an empty user page table that shares the kernel's mappings and
+    /// returns its P4 frame.
+    pub fn new_user_table(&mut self) -> Option<PhysFrame> {
+        let frame = self.frame_allocator.allocate_frame()?;
+        let table = InactivePageTable::new_sharing_kernel(frame, &mut self.active_table, &mut self.temporary_page);
+        Some(table.p4_frame)
+    }
+
+    /// Maps fresh zeroed frames, readable and writable by user code, over
+    /// every page from `start` to `end` in the active table. Pages already
+    /// mapped are left alone.
+    pub fn map_user_pages(&mut self, start: Page, end: Page) -> Option<()> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for page in Page::range_inclusive(start, end) {
+            if self.active_table.translate_page(page).is_ok() {
+                continue;
+            }
+            let frame = self.frame_allocator.allocate_frame()?;
+            unsafe {
+                self.active_table.map_to(page, frame, flags, &mut self.frame_allocator)
+                    .expect("failed to map user page").flush();
+            }
+            paging::set_user_accessible_parents(page);
+            self.user_frames += 1;
+            unsafe {
+                core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+            }
+        }
+        Some(())
+    }
+
+    /// Allocates a frame holding a copy of `page` of the active table.
+    fn copy_page(&mut self, page: Page) -> Option<PhysFrame> {
+        let frame = self.frame_allocator.allocate_frame()?;
+        let copy = self.temporary_page.map(frame, &mut self.active_table);
+        unsafe {
+            core::ptr::copy_nonoverlapping(page.start_address().as_ptr::<u8>(), copy.as_mut_ptr::<u8>(),
+                Size4KiB::SIZE as usize);
+        }
+        self.temporary_page.unmap(&mut self.active_table);
+        Some(frame)
+    }
+
+    /// Makes a new user page table mapping those of `pages` that are mapped
+    /// in the active table, each given to it as its ForkPage says, and
+    /// returns its P4 frame. `mappings` is scratch space and must have room
+    /// for all of `pages`, since the heap can't grow while the controller is
+    /// held.
+    pub fn fork_user_pages(&mut self, pages: &[(Page, PageTableFlags, ForkPage)],
+                           mappings: &mut Vec<(Page, PhysFrame, PageTableFlags)>) -> Option<PhysFrame> {
+        assert!(mappings.capacity() >= pages.len(), "no room to fork user pages");
+        mappings.clear();
+        let mut copied = 0;
+        for &(page, flags, how) in pages {
+            let mut frame = match self.active_table.translate_page(page) {
+                Ok(frame) => frame,
+                Err(_) => continue
+            };
+
+            if how == ForkPage::Share {
+                // both must write to the one frame, so it can't stay
+                // copy-on-write
+                if paging::page_flags(page).map_or(false, |mapped| mapped.contains(COPY_ON_WRITE)) {
+                    if !self.resolve_user_fault(page, flags, true, false) {
+                        return self.abandon_fork(mappings);
+                    }
+                    frame = self.active_table.translate_page(page).expect("mapped page has no frame");
+                }
+                if !self.frame_allocator.share(frame) {
+                    return self.abandon_fork(mappings);
+                }
+                mappings.push((page, frame, flags));
+                continue;
+            }
+
+            if how == ForkPage::CopyOnWrite && self.frame_allocator.share(frame) {
+                let flags = if flags.contains(PageTableFlags::WRITABLE) {
+                    let cow = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    unsafe {
+                        self.active_table.update_flags(page, cow | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+                            .expect("failed to update user page flags").flush();
+                    }
+                    cow
+                } else {
+                    flags
+                };
+                mappings.push((page, frame, flags));
+                continue;
+            }
+
+            // to be copied, or shared by too many already
+            match self.copy_page(page) {
+                Some(copy) => {
+                    mappings.push((page, copy, flags));
+                    copied += 1;
+                }
+                None => return self.abandon_fork(mappings)
+            }
+        }
+
+        let mut table = match self.new_user_table() {
+            Some(frame) => InactivePageTable { p4_frame: frame },
+            None => return self.abandon_fork(mappings)
+        };
+        self.user_frames += copied;
+        let MemoryController { ref mut active_table, ref mut frame_allocator, ref mut temporary_page, .. } = *self;
+        active_table.with(&mut table, temporary_page, |mapper| {
+            for (page, frame, flags) in mappings.drain(..) {
+                let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                unsafe {
+                    mapper.map_to(page, frame, flags, frame_allocator)
+                        .expect("failed to map user page").flush();
+                }
+                paging::set_user_accessible_parents(page);
+            }
+        });
+        Some(table.p4_frame)
+    }
+
+    /// Gives back the frames a failed fork_user_pages took.
+    fn abandon_fork(&mut self, mappings: &mut Vec<(Page, PhysFrame, PageTableFlags)>) -> Option<PhysFrame> {
+        for (_, frame, _) in mappings.drain(..) {
+            self.frame_allocator.release(frame);
+        }
+        None
+    }
+
+    /// The frame `page` is mapped to in the inactive table at `p4_frame`.
+    fn translate_inactive(&mut self, p4_frame: PhysFrame, page: Page) -> Option<PhysFrame> {
+        let mut table = InactivePageTable { p4_frame };
+        let mut frame = None;
+        self.active_table.with(&mut table, &mut self.temporary_page, |mapper| frame = mapper.translate_page(page).ok());
+        frame
+    }
+
+    /// Like resolve_user_fault, for a page of a segment shared with the
+    /// other page tables in `tables`. A page missing here is mapped to the
+    /// frame one of them has for it, so that each sees the other's writes.
+    pub fn resolve_shared_fault(&mut self, page: Page, flags: PageTableFlags, write: bool, fetch: bool,
+                                tables: &[PhysFrame]) -> bool {
+        if paging::page_flags(page).is_none() {
+            if (write && !flags.contains(PageTableFlags::WRITABLE)) || (fetch && flags.contains(PageTableFlags::NO_EXECUTE)) {
+                return false;
+            }
+            let active = Cr3::read().0;
+            for &table in tables.iter().filter(|t| **t != active) {
+                if let Some(frame) = self.translate_inactive(table, page) {
+                    if !self.frame_allocator.share(frame) {
+                        return false;
+                    }
+                    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                    unsafe {
+                        self.active_table.map_to(page, frame, flags, &mut self.frame_allocator)
+                            .expect("failed to map user page").flush();
+                    }
+                    paging::set_user_accessible_parents(page);
+                    return true;
+                }
+            }
+        }
+        self.resolve_user_fault(page, flags, write, fetch)
+    }
+
+    /// Resolves a fault on `page` of the active table, which a segment with
+    /// `flags` covers. A missing page is mapped zeroed; a write to a
+    /// copy-on-write page gets a copy of its own, or the page itself if no
+    /// one else shares it any more. Returns false if the access can't be
+    /// allowed.
+    pub fn resolve_user_fault(&mut self, page: Page, flags: PageTableFlags, write: bool, fetch: bool) -> bool {
+        if write && !flags.contains(PageTableFlags::WRITABLE) {
+            return false;
+        }
+        if fetch && flags.contains(PageTableFlags::NO_EXECUTE) {
+            return false;
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let mapped = match paging::page_flags(page) {
+            Some(mapped) => mapped,
+            None => {
+                if self.map_user_pages(page, page).is_none() {
+                    return false;
+                }
+                self.set_user_flags(page, page, flags);
+                return true;
+            }
+        };
+        if !write || mapped.contains(PageTableFlags::WRITABLE) {
+            // already allowed, the fault came from a stale TLB entry
+            x86_64::instructions::tlb::flush(page.start_address());
+            return true;
+        }
+        if !mapped.contains(COPY_ON_WRITE) {
+            return false;
+        }
+
+        let frame = self.active_table.translate_page(page).expect("mapped page has no frame");
+        if self.frame_allocator.is_shared(frame) {
+            let copy = match self.copy_page(page) {
+                Some(copy) => copy,
+                None => return false
+            };
+            unsafe {
+                self.active_table.unmap(page).expect("failed to unmap user page").1.flush();
+                self.active_table.map_to(page, copy, flags, &mut self.frame_allocator)
+                    .expect("failed to map user page").flush();
+            }
+            self.frame_allocator.release(frame);
+            self.user_frames += 1;
+        } else {
+            unsafe {
+                self.active_table.update_flags(page, flags)
+                    .expect("failed to update user page flags").flush();
+            }
+        }
+        true
+    }
+
+    /// Unmaps whatever is mapped from `start` to `end` in the active table,
+    /// freeing the frames no other table shares.
+    pub fn unmap_user_pages(&mut self, start: Page, end: Page) {
+        for page in Page::range_inclusive(start, end) {
+            if let Ok((frame, flush)) = self.active_table.unmap(page) {
+                flush.flush();
+                if self.frame_allocator.release(frame) {
+                    self.user_frames -= 1;
+                }
+            }
+        }
+    }
+
+    /// Like unmap_user_pages, in the inactive table at `p4_frame`.
+    pub fn unmap_inactive_user_pages(&mut self, p4_frame: PhysFrame, start: Page, end: Page) {
+        let mut table = InactivePageTable { p4_frame };
+        let MemoryController { ref mut active_table, ref mut frame_allocator, ref mut temporary_page, .. } = *self;
+        let mut freed = 0;
+        active_table.with(&mut table, temporary_page, |mapper| {
+            for page in Page::range_inclusive(start, end) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    // with flushes everything when done
+                    flush.ignore();
+                    if frame_allocator.release(frame) {
+                        freed += 1;
+                    }
+                }
+            }
+        });
+        self.user_frames -= freed;
+    }
+
+    /// Frees a user page table made by `new_user_table`, and every frame
+    /// mapped in its user part. If it is loaded, the kernel's table is
+    /// loaded in its place first.
+    pub fn free_user_table(&mut self, frame: PhysFrame) {
+        if Cr3::read().0 == frame {
+            switch_table(self.kernel_table);
+        }
+        let mut table = InactivePageTable { p4_frame: frame };
+        let MemoryController { ref mut active_table, ref mut frame_allocator, ref mut temporary_page, .. } = *self;
+        let mut freed = 0;
+        active_table.with(&mut table, temporary_page, |_| freed = paging::free_user_mappings(frame_allocator));
+        frame_allocator.deallocate_frame(frame);
+        self.user_frames -= freed;
+    }
+
+    /// Maps fresh frames, writable by the kernel only, over every page from
+    /// `start` to `end` that isn't mapped already.
+    pub fn map_kernel_pages(&mut self, start: Page, end: Page) -> Option<()> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for page in Page::range_inclusive(start, end) {
+            if self.active_table.translate_page(page).is_ok() {
+                continue;
+            }
+            let frame = self.frame_allocator.allocate_frame()?;
+            unsafe {
+                self.active_table.map_to(page, frame, flags, &mut self.frame_allocator)
+                    .expect("failed to map kernel page").flush();
+            }
+        }
+        Some(())
+    }
+
+    /// Changes the flags of mapped user pages from `start` to `end`.
+    pub fn set_user_flags(&mut self, start: Page, end: Page, flags: PageTableFlags) {
+        for page in Page::range_inclusive(start, end) {
+            unsafe {
+                self.active_table.update_flags(page, flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+                    .expect("failed to update user page flags").flush();
+            }
+        }
+    }
+}
+
+/// Marks a user page that is shared read-only until written to.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// How fork_user_pages gives a page to the new table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkPage {
+    /// The same frame, copy-on-write in both tables if writable.
+    CopyOnWrite,
+    /// A copy of its own.
+    Copy,
+    /// The same frame, writable by both if the segment is.
+    Share,
+}
+
+/// Loads the page table with P4 frame `frame`.
+pub fn switch_table(frame: PhysFrame) {
+    let (current, flags) = Cr3::read();
+    if current != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
+
+static MEMORY_CONTROLLER: Mutex<Option<MemoryController>> = Mutex::new(None);
+
+/// Runs `f` with the memory controller. Interrupts are held off meanwhile so
+/// an interrupt handler can never find the controller locked. The heap grows
+/// through the controller, so `f` must not allocate.
+pub fn with_controller<F, R>(f: F) -> R
+    where F: FnOnce(&mut MemoryController) -> R
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut controller = MEMORY_CONTROLLER.lock();
+        f(controller.as_mut().expect("memory not initialised"))
+    })
+}
+
+/// Maps `size` more bytes of heap at `top`, as long as the heap stays
+/// within HEAP_MAX_SIZE. Returns whether it did.
+pub fn grow_heap(top: usize, size: usize) -> bool {
+    use {HEAP_START, HEAP_MAX_SIZE};
+
+    if top + size > HEAP_START + HEAP_MAX_SIZE {
+        return false;
+    }
+    let start = Page::containing_address(VirtAddr::new(top as u64));
+    let end = Page::containing_address(VirtAddr::new((top + size - 1) as u64));
+    with_controller(|mc| mc.map_kernel_pages(start, end)).is_some()
+}
+
+pub fn init<'a>(boot_info: &'static BootInformation) -> initrd::InitRD<'a> {
     assert_has_not_been_called!("memory::init must be called only once");
 
     let memory_map_tag = boot_info.memory_map_tag()
@@ -50,15 +428,15 @@ pub fn init<'a>(boot_info: BootInformation) -> initrd::InitRD<'a> {
     let mut modules = boot_info.module_tags();
     let initrd = modules.next().expect("initrd module not in multiboot info");
 
-    let mut frame_allocator = AreaFrameAllocator::new(
+    let mut frame_allocator = BitmapFrameAllocator::new(
         PhysAddr::new(kernel_start), PhysAddr::new(kernel_end),
         PhysAddr::new(modules_start.into()), PhysAddr::new(modules_end.into()),
         PhysAddr::new(boot_info.start_address() as u64), PhysAddr::new(boot_info.end_address() as u64),
         memory_map_tag.memory_areas());
 
-    let mut active_table = remap_the_kernel(&mut frame_allocator, &boot_info);
+    let mut active_table = remap_the_kernel(&mut frame_allocator, boot_info);
 
-    use {HEAP_START, HEAP_SIZE, INITRD_START};
+    use {HEAP_START, HEAP_SIZE, INITRD_START, KSTACK_START, KSTACK_END, TEMPORARY_PAGE, FRAMEBUFFER_START};
 
     let heap_start_page =
         Page::containing_address(VirtAddr::new(HEAP_START.try_into().unwrap()));
@@ -92,6 +470,42 @@ pub fn init<'a>(boot_info: BootInformation) -> initrd::InitRD<'a> {
         }
     }
 
+    // the boot loader's framebuffer, if it set a graphics mode we can use
+    if let Some(tag) = boot_info.framebuffer_tag().filter(framebuffer::usable) {
+        let size = u64::from(tag.pitch) * u64::from(tag.height);
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(tag.address));
+        let end_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(tag.address + size - 1));
+        let start_page = Page::containing_address(VirtAddr::new(FRAMEBUFFER_START as u64));
+        for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe {
+                active_table.map_to(start_page + i as u64, frame, flags, &mut frame_allocator)
+                    .expect("failed to map framebuffer page").flush();
+            }
+        }
+    }
+
+    let stack_allocator = {
+        let stack_alloc_start = Page::containing_address(VirtAddr::new(KSTACK_START as u64));
+        let stack_alloc_end = Page::containing_address(VirtAddr::new((KSTACK_END - 1) as u64));
+        StackAllocator::new(Page::range_inclusive(stack_alloc_start, stack_alloc_end))
+    };
+
+    let temporary_page = TemporaryPage::new(
+        Page::containing_address(VirtAddr::new(TEMPORARY_PAGE as u64)),
+        &mut frame_allocator);
+
+    *MEMORY_CONTROLLER.lock() = Some(MemoryController {
+        active_table,
+        frame_allocator,
+        stack_allocator,
+        temporary_page,
+        kernel_table: Cr3::read().0,
+        user_frames: 0,
+    });
+    let (total, free) = with_controller(|mc| mc.frame_counts());
+    println!("{} KiB of {} KiB physical memory free", free * 4, total * 4);
+
     initrd::InitRD::new(
         VirtAddr::new(INITRD_START as u64),
         VirtAddr::new((INITRD_START + initrd_size) as u64)
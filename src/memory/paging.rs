@@ -2,8 +2,11 @@ use core::ops::{Deref, DerefMut};
 use x86_64::registers::control;
 use x86_64::instructions::tlb;
 use x86_64::ux::u9;
-use x86_64::structures::paging::{Page, PageTable, RecursivePageTable, PhysFrame, PageTableFlags};
+use x86_64::structures::paging::{Page, PageTable, RecursivePageTable, PhysFrame, PageTableFlags, FrameDeallocator};
+use x86_64::structures::paging::page::Size4KiB;
 use super::temporary_page::TemporaryPage;
+use super::BitmapFrameAllocator;
+use crate::{USER_START, USER_END};
 
 pub struct ActivePageTable<'a> {
     mapper: RecursivePageTable<'a>,
@@ -84,6 +87,98 @@ pub struct InactivePageTable {
     pub p4_frame: PhysFrame,
 }
 
+/// Sets USER_ACCESSIBLE on the P4, P3 and P2 entries leading to `page` in
+/// the active table. `map_to` creates missing tables without it, and ring 3
+/// needs it at every level.
+pub fn set_user_accessible_parents(page: Page) {
+    let r = u9::new(511);
+    let p3 = Page::<Size4KiB>::from_page_table_indices(r, r, r, page.p4_index());
+    let p2 = Page::<Size4KiB>::from_page_table_indices(r, r, page.p4_index(), page.p3_index());
+
+    let entries = [
+        &mut page_table()[page.p4_index()],
+        &mut unsafe { &mut *(p3.start_address().as_mut_ptr() as *mut PageTable) }[page.p3_index()],
+        &mut unsafe { &mut *(p2.start_address().as_mut_ptr() as *mut PageTable) }[page.p2_index()],
+    ];
+    for entry in entries.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            let addr = entry.addr();
+            entry.set_addr(addr, flags | PageTableFlags::USER_ACCESSIBLE);
+        }
+    }
+    tlb::flush(page.start_address());
+}
+
+/// The table the recursive mapping shows at the given indices.
+fn table_at<'a>(p4: u9, p3: u9, p2: u9, p1: u9) -> &'a mut PageTable {
+    let page = Page::<Size4KiB>::from_page_table_indices(p4, p3, p2, p1);
+    unsafe { &mut *(page.start_address().as_mut_ptr() as *mut PageTable) }
+}
+
+/// The flags of the active table's entry for `page`, or `None` if it isn't
+/// mapped.
+pub fn page_flags(page: Page) -> Option<PageTableFlags> {
+    let r = u9::new(511);
+    if page_table()[page.p4_index()].is_unused() {
+        return None;
+    }
+    if table_at(r, r, r, page.p4_index())[page.p3_index()].is_unused() {
+        return None;
+    }
+    if table_at(r, r, page.p4_index(), page.p3_index())[page.p2_index()].is_unused() {
+        return None;
+    }
+    let entry = &table_at(r, page.p4_index(), page.p3_index(), page.p2_index())[page.p1_index()];
+    if entry.is_unused() {
+        None
+    } else {
+        Some(entry.flags())
+    }
+}
+
+/// Drops every mapping in the user part of the active table and frees the
+/// tables, leaving the user part of the P4 empty. Returns how many mapped
+/// frames, not counting tables, were freed rather than still shared.
+pub fn free_user_mappings(allocator: &mut BitmapFrameAllocator) -> usize {
+    let mut freed = 0;
+    let r = u9::new(511);
+    let first = USER_START >> 39;
+    let last = (USER_END - 1) >> 39;
+    let p4 = page_table();
+    for i in first..=last {
+        if p4[i].is_unused() {
+            continue;
+        }
+        let i9 = u9::new(i as u16);
+        let p3 = table_at(r, r, r, i9);
+        for j in 0..512 {
+            if p3[j].is_unused() {
+                continue;
+            }
+            let j9 = u9::new(j as u16);
+            let p2 = table_at(r, r, i9, j9);
+            for k in 0..512 {
+                if p2[k].is_unused() {
+                    continue;
+                }
+                let p1 = table_at(r, i9, j9, u9::new(k as u16));
+                for l in 0..512 {
+                    if !p1[l].is_unused() && allocator.release(PhysFrame::containing_address(p1[l].addr())) {
+                        freed += 1;
+                    }
+                }
+                allocator.deallocate_frame(PhysFrame::containing_address(p2[k].addr()));
+            }
+            allocator.deallocate_frame(PhysFrame::containing_address(p3[j].addr()));
+        }
+        allocator.deallocate_frame(PhysFrame::containing_address(p4[i].addr()));
+        p4[i].set_unused();
+    }
+    tlb::flush_all();
+    freed
+}
+
 impl InactivePageTable {
     pub fn new(frame: PhysFrame,
                active_table: &mut ActivePageTable,
@@ -100,4 +195,25 @@ impl InactivePageTable {
 
         InactivePageTable { p4_frame: frame }
     }
+
+    /// Like `new`, but the table shares the active table's first P4 entry,
+    /// where the kernel lives, so the kernel stays mapped while it is in use.
+    pub fn new_sharing_kernel(frame: PhysFrame,
+                              active_table: &mut ActivePageTable,
+                              temporary_page: &mut TemporaryPage)
+                              -> InactivePageTable {
+        let (kernel_addr, kernel_flags) = {
+            let entry = &page_table()[0];
+            (entry.addr(), entry.flags())
+        };
+        {
+            let table = temporary_page.map_table_frame(frame.clone(), active_table);
+            table.zero();
+            table[0].set_addr(kernel_addr, kernel_flags);
+            table[511].set_frame(frame.clone(), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+        temporary_page.unmap(active_table);
+
+        InactivePageTable { p4_frame: frame }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,110 @@
+//! Caches of fixed size objects in front of the heap, so the many small
+//! allocations don't each have to search the hole list.
+
+use alloc::alloc::Layout;
+use core::cmp;
+use core::ptr::{self, NonNull};
+use super::heap_allocator::{self, Heap};
+
+pub const NUM_CLASSES: usize = 8;
+pub const SIZE_CLASSES: [usize; NUM_CLASSES] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The least a cache takes from the heap when it runs out.
+const MIN_SLAB_SIZE: usize = 4096;
+const MIN_SLAB_OBJECTS: usize = 8;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub size: usize,
+    /// Allocations served from the free list.
+    pub hits: usize,
+    /// Allocations that had to take a new slab from the heap.
+    pub misses: usize,
+    pub slabs: usize,
+    pub free: usize,
+}
+
+pub struct SlabCache {
+    size: usize,
+    free_list: *mut FreeObject,
+    stats: SlabStats,
+}
+
+// The free list is only touched with the allocator locked.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(size: usize) -> SlabCache {
+        SlabCache {
+            size,
+            free_list: 0 as *mut FreeObject,
+            stats: SlabStats {
+                size,
+                hits: 0,
+                misses: 0,
+                slabs: 0,
+                free: 0,
+            },
+        }
+    }
+
+    /// Carves a new slab from the heap into free objects.
+    fn refill(&mut self, heap: &mut Heap) -> bool {
+        let slab_size = slab_objects(self.size) * self.size;
+        let layout = Layout::from_size_align(slab_size, self.size).unwrap();
+        let slab = match heap_allocator::allocate_growing(heap, layout) {
+            Some(slab) => slab.as_ptr() as usize,
+            None => return false
+        };
+        for i in (0..slab_size / self.size).rev() {
+            let object = (slab + i * self.size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: self.free_list }) };
+            self.free_list = object;
+        }
+        self.stats.slabs += 1;
+        self.stats.free += slab_size / self.size;
+        true
+    }
+
+    pub fn allocate(&mut self, heap: &mut Heap) -> Option<NonNull<u8>> {
+        if self.free_list.is_null() {
+            self.stats.misses += 1;
+            if !self.refill(heap) {
+                return None;
+            }
+        } else {
+            self.stats.hits += 1;
+        }
+        let object = self.free_list;
+        self.free_list = unsafe { (*object).next };
+        self.stats.free -= 1;
+        NonNull::new(object as *mut u8)
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let object = ptr.as_ptr() as *mut FreeObject;
+        ptr::write(object, FreeObject { next: self.free_list });
+        self.free_list = object;
+        self.stats.free += 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+}
+
+/// The number of objects in each slab of a cache of `size` byte objects.
+pub fn slab_objects(size: usize) -> usize {
+    cmp::max(MIN_SLAB_SIZE, size * MIN_SLAB_OBJECTS) / size
+}
+
+/// The cache for allocations of `layout`, or `None` if they're too big for
+/// any and should go to the heap.
+pub fn size_class(layout: &Layout) -> Option<usize> {
+    let size = cmp::max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
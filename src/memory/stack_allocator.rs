@@ -0,0 +1,84 @@
+use super::paging::ActivePageTable;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::page::{PageRangeInclusive, Size4KiB};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, FrameAllocator, Mapper};
+
+/// A kernel stack. The page below `bottom` is never mapped, so running off
+/// the end of the stack faults instead of corrupting whatever lies below.
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtAddr,
+    bottom: VirtAddr,
+}
+
+impl Stack {
+    fn new(top: VirtAddr, bottom: VirtAddr) -> Stack {
+        assert!(top > bottom);
+        Stack {
+            top,
+            bottom,
+        }
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+}
+
+/// Hands out stacks from a range of virtual memory, leaving a guard page
+/// in front of each.
+pub struct StackAllocator {
+    range: PageRangeInclusive,
+}
+
+impl StackAllocator {
+    pub fn new(range: PageRangeInclusive) -> StackAllocator {
+        StackAllocator { range }
+    }
+
+    pub fn alloc_stack<A>(&mut self, active_table: &mut ActivePageTable, frame_allocator: &mut A,
+                          size_in_pages: usize) -> Option<Stack>
+        where A: FrameAllocator<Size4KiB>
+    {
+        if size_in_pages == 0 {
+            return None;
+        }
+
+        // clone the range, since we only want to change it on success
+        let mut range = self.range.clone();
+
+        let guard_page = range.next();
+        let stack_start = range.next();
+        let stack_end = if size_in_pages == 1 {
+            stack_start
+        } else {
+            // choose the (size_in_pages-2)th element, since index starts at 0
+            // and we already allocated the start page
+            range.nth(size_in_pages - 2)
+        };
+
+        match (guard_page, stack_start, stack_end) {
+            (Some(_), Some(start), Some(end)) => {
+                self.range = range;
+
+                for page in Page::range_inclusive(start, end) {
+                    let frame = frame_allocator.allocate_frame()?;
+                    unsafe {
+                        active_table.map_to(page, frame,
+                                            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                                            frame_allocator)
+                            .expect("failed to map stack page").flush();
+                    }
+                }
+
+                let top_of_stack = end.start_address() + Size4KiB::SIZE;
+                Some(Stack::new(top_of_stack, start.start_address()))
+            }
+            _ => None, // not enough pages
+        }
+    }
+}
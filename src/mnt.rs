@@ -0,0 +1,260 @@
+//! The mount driver, `#M`. Each mount starts a session that speaks 9P over
+//! an open file descriptor; `#M<n>` attaches to session `n`.
+
+use lazy_static::lazy_static;
+use alloc::collections;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use spin::Mutex;
+use crate::nine_p;
+use crate::nine_p::fcall::{self, Fcall, Msg};
+use crate::nine_p::qidpool::Qid;
+use crate::proc;
+
+/// The largest message we ask servers to send.
+const MAX_MSIZE: u32 = 8192 + IOHDRSZ;
+
+/// Room left in a message for everything but the data of a read or write.
+const IOHDRSZ: u32 = 24;
+
+/// NOFID as it goes over the wire.
+const WIRE_NO_FID: nine_p::Fid = !0;
+
+struct Session {
+    fd: proc::FdRef,
+    aname: String,
+    msize: u32,
+    next_tag: fcall::Tag,
+    /// Bytes read past the end of the last reply.
+    pending: Vec<u8>,
+}
+
+impl Session {
+    fn send(&mut self, msg: Msg) -> nine_p::Result<()> {
+        let out = Fcall::new(self.next_tag, msg).as_bytes();
+        let fd = self.fd.lock();
+        let mut written = 0;
+        while written < out.len() {
+            written += fd.chan().write(0, &out[written..])?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> nine_p::Result<Fcall> {
+        loop {
+            if let Some(size) = fcall::frame_size(&self.pending) {
+                if size > self.msize as usize {
+                    return Err(nine_p::DevError::BadMessage);
+                }
+                if self.pending.len() >= size {
+                    let rest = self.pending.split_off(size);
+                    let frame = core::mem::replace(&mut self.pending, rest);
+                    return Fcall::from_bytes(&frame);
+                }
+            }
+            let buf = self.fd.lock().chan().read(0, self.msize as usize)?;
+            if buf.is_empty() {
+                return Err(nine_p::DevError::Str("mount rpc error".to_owned()));
+            }
+            self.pending.extend_from_slice(&buf);
+        }
+    }
+
+    /// Sends `msg` and waits for its reply, turning Rerror into an error.
+    fn rpc(&mut self, msg: Msg) -> nine_p::Result<Msg> {
+        self.send(msg)?;
+        let reply = self.receive()?;
+        if reply.tag() != self.next_tag {
+            return Err(nine_p::DevError::Str("mount rpc error".to_owned()));
+        }
+        self.next_tag = (self.next_tag + 1) % fcall::NO_TAG;
+        match reply.into_msg() {
+            Msg::Rerror { ename } => Err(nine_p::DevError::Str(ename)),
+            msg => Ok(msg)
+        }
+    }
+
+    fn iounit(&self) -> usize {
+        (self.msize - IOHDRSZ) as usize
+    }
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<collections::BTreeMap<u32, Arc<Mutex<Session>>>> =
+        Mutex::new(collections::BTreeMap::new());
+
+    /// The session each of our fids belongs to. The remote fid is the same
+    /// number.
+    static ref FIDS: Mutex<collections::BTreeMap<nine_p::Fid, u32>> =
+        Mutex::new(collections::BTreeMap::new());
+}
+
+/// Negotiates a 9P session with the server at the other end of `fd`,
+/// returning the number to attach to it by.
+pub fn new_session(fd: proc::FdRef, aname: &str) -> nine_p::Result<u32> {
+    let mut session = Session {
+        fd,
+        aname: aname.to_owned(),
+        msize: MAX_MSIZE,
+        next_tag: fcall::NO_TAG,
+        pending: Vec::new(),
+    };
+    match session.rpc(Msg::Tversion { msize: MAX_MSIZE, version: fcall::VERSION.to_owned() })? {
+        Msg::Rversion { msize, version } => {
+            if version != fcall::VERSION || msize <= IOHDRSZ || msize > MAX_MSIZE {
+                return Err(nine_p::DevError::Str("server speaks an unknown 9P version".to_owned()));
+            }
+            session.msize = msize;
+        }
+        _ => return Err(nine_p::DevError::BadMessage)
+    }
+    session.next_tag = 0;
+
+    let mut sessions = SESSIONS.lock();
+    let id = match sessions.keys().next_back() {
+        Some(id) => id + 1,
+        None => 0
+    };
+    sessions.insert(id, Arc::new(Mutex::new(session)));
+    Ok(id)
+}
+
+/// Keeps no state of its own, so requests can be made through any number
+/// of them at once; only a session is locked while its server is waited on.
+#[derive(Debug)]
+pub struct MntServer;
+
+impl MntServer {
+    pub fn new() -> Self {
+        MntServer
+    }
+
+    fn session(&self, fid: nine_p::Fid) -> nine_p::Result<Arc<Mutex<Session>>> {
+        let id = *FIDS.lock().get(&fid).ok_or(nine_p::DevError::NoFid)?;
+        SESSIONS.lock().get(&id).cloned().ok_or(nine_p::DevError::Str("mount session gone".to_owned()))
+    }
+
+    fn rpc(&self, fid: nine_p::Fid, msg: Msg) -> nine_p::Result<Msg> {
+        self.session(fid)?.lock().rpc(msg)
+    }
+}
+
+impl nine_p::NinePServer for MntServer {
+    fn name(&self) -> char {
+        'M'
+    }
+
+    fn description(&self) -> &'static str {
+        "mount driver"
+    }
+
+    fn unlocked(&self) -> Option<Box<dyn nine_p::NinePServer>> {
+        Some(Box::new(MntServer))
+    }
+
+    fn auth(&mut self, _afid: nine_p::Fid, _uname: &str, _aname: &str) -> nine_p::Result<Qid> {
+        Err(nine_p::DevError::AuthNotNeeded)
+    }
+
+    fn attach(&mut self, fid: nine_p::Fid, _afid: nine_p::Fid, uname: &str, aname: &str) -> nine_p::Result<Qid> {
+        if FIDS.lock().contains_key(&fid) {
+            return Err(nine_p::DevError::FidInUse);
+        }
+        let id: u32 = aname.parse().map_err(|_| nine_p::DevError::NoSuchFile)?;
+        let session = SESSIONS.lock().get(&id).cloned().ok_or(nine_p::DevError::NoSuchFile)?;
+
+        let mut session = session.lock();
+        let aname = session.aname.clone();
+        match session.rpc(Msg::Tattach { fid, afid: WIRE_NO_FID, uname: uname.to_owned(), aname })? {
+            Msg::Rattach { qid } => {
+                FIDS.lock().insert(fid, id);
+                Ok(qid)
+            }
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn clunk(&mut self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        let res = self.rpc(fid, Msg::Tclunk { fid });
+        FIDS.lock().remove(&fid);
+        res.map(|_| ())
+    }
+
+    fn open(&mut self, fid: nine_p::Fid, mode: &nine_p::FileMode) -> nine_p::Result<(Qid, u32)> {
+        match self.rpc(fid, Msg::Topen { fid, mode: mode.bits() })? {
+            Msg::Ropen { qid, iounit } => Ok((qid, iounit)),
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn create(&mut self, fid: nine_p::Fid, name: &str, perm: u32, mode: &nine_p::FileMode) -> nine_p::Result<(Qid, u32)> {
+        match self.rpc(fid, Msg::Tcreate { fid, name: name.to_owned(), perm, mode: mode.bits() })? {
+            Msg::Rcreate { qid, iounit } => Ok((qid, iounit)),
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn walk(&mut self, fid: nine_p::Fid, new_fid: nine_p::Fid, names: &[&str]) -> nine_p::Result<Vec<Qid>> {
+        if names.len() > fcall::MAX_WALK_ELEMENTS {
+            return Err(nine_p::DevError::Str("too many path elements".to_owned()));
+        }
+        let id = {
+            let fids = FIDS.lock();
+            if new_fid != fid && fids.contains_key(&new_fid) {
+                return Err(nine_p::DevError::FidInUse);
+            }
+            *fids.get(&fid).ok_or(nine_p::DevError::NoFid)?
+        };
+
+        let wnames = names.iter().map(|n| (*n).to_owned()).collect();
+        match self.rpc(fid, Msg::Twalk { fid, newfid: new_fid, wnames })? {
+            Msg::Rwalk { wqids } => {
+                if wqids.len() == names.len() {
+                    FIDS.lock().insert(new_fid, id);
+                }
+                Ok(wqids)
+            }
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn read(&mut self, fid: nine_p::Fid, offset: u64, count: usize) -> nine_p::Result<Vec<u8>> {
+        let session = self.session(fid)?;
+        let mut session = session.lock();
+        let count = core::cmp::min(count, session.iounit()) as u32;
+        match session.rpc(Msg::Tread { fid, offset, count })? {
+            Msg::Rread { data } => Ok(data),
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn write(&mut self, fid: nine_p::Fid, offset: u64, data: &[u8]) -> nine_p::Result<usize> {
+        let session = self.session(fid)?;
+        let mut session = session.lock();
+        let count = core::cmp::min(data.len(), session.iounit());
+        match session.rpc(Msg::Twrite { fid, offset, data: data[..count].to_vec() })? {
+            Msg::Rwrite { count } => Ok(count as usize),
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn remove(&mut self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        let res = self.rpc(fid, Msg::Tremove { fid });
+        FIDS.lock().remove(&fid);
+        res.map(|_| ())
+    }
+
+    fn stat(&mut self, fid: nine_p::Fid) -> nine_p::Result<nine_p::dir::Dir> {
+        match self.rpc(fid, Msg::Tstat { fid })? {
+            Msg::Rstat { stat } => nine_p::dir::Dir::from_bytes(&stat),
+            _ => Err(nine_p::DevError::BadMessage)
+        }
+    }
+
+    fn wstat(&mut self, fid: nine_p::Fid, dir: &nine_p::dir::Dir) -> nine_p::Result<()> {
+        self.rpc(fid, Msg::Twstat { fid, stat: dir.as_bytes() }).map(|_| ())
+    }
+}
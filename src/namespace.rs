@@ -1,41 +1,309 @@
 use alloc::collections;
 use alloc::string::String;
+use alloc::vec::Vec;
 use alloc::borrow::ToOwned;
+use alloc::vec;
 use crate::dev;
 use crate::nine_p;
 
+bitflags! {
+    pub struct BindFlags: u32 {
+        /// Replace whatever is bound at the mount point.
+        const MREPL = 0x0000;
+        /// Add to the front of the union at the mount point.
+        const MBEFORE = 0x0001;
+        /// Add to the back of the union at the mount point.
+        const MAFTER = 0x0002;
+        const MORDER = 0x0003;
+        /// Files created in the union go to this member.
+        const MCREATE = 0x0004;
+    }
+}
+
+/// How deep member paths may refer back into the namespace before we
+/// assume the binds form a loop.
+const MAX_BIND_DEPTH: usize = 8;
+
+#[derive(Debug, Clone)]
+struct Mount {
+    to: String,
+    create: bool,
+    /// The directory that was at the mount point before it became a union,
+    /// found by resolving `to` while ignoring the mount point itself.
+    base: bool,
+}
+
+/// Lexically cleans a path the way Plan 9's cleanname does: repeated
+/// slashes and `.` elements are removed, `..` eats the element before it
+/// and `..` at the root stays at the root. A leading `#X` device name is
+/// treated as a root.
+pub fn clean_name(path: &str) -> String {
+    let (root, rest) = if path.starts_with('#') {
+        match path.find('/') {
+            Some(i) => (&path[..i], &path[i..]),
+            None => (path, "")
+        }
+    } else {
+        ("", path)
+    };
+    let rooted = !root.is_empty() || rest.starts_with('/');
+
+    let mut elems: Vec<&str> = Vec::new();
+    for elem in rest.split('/') {
+        match elem {
+            "" | "." => {}
+            ".." => {
+                match elems.last() {
+                    Some(&"..") | None => {
+                        if !rooted {
+                            elems.push("..");
+                        }
+                    }
+                    Some(_) => {
+                        elems.pop();
+                    }
+                }
+            }
+            elem => elems.push(elem)
+        }
+    }
+
+    let mut out = String::from(root);
+    if rooted {
+        out.push('/');
+    }
+    out.push_str(&elems.join("/"));
+    if out.len() > 1 && out.ends_with('/') {
+        out.pop();
+    }
+    if out.is_empty() {
+        out.push('.');
+    }
+    out
+}
+
+/// Splits a cleaned path into its elements.
+fn elements(path: &str) -> Vec<&str> {
+    path.split('/').filter(|e| !e.is_empty()).collect()
+}
+
+/// Whether `prefix` is `path` or one of its ancestor directories.
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    if prefix == "/" {
+        path.starts_with('/')
+    } else {
+        path == prefix || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+    }
+}
+
+/// A process's view of the file tree: every mount point maps to the
+/// ordered members of its union.
+#[derive(Debug, Clone)]
 pub struct Namespace {
-    binds: collections::BTreeMap<String, String>
+    mounts: collections::BTreeMap<String, Vec<Mount>>
 }
 
 impl Namespace {
     pub fn new() -> Self {
         Self {
-            binds: collections::BTreeMap::new()
+            mounts: collections::BTreeMap::new()
         }
     }
 
-    pub fn bind(&mut self, src: &str, dst: &str) {
-        self.binds.insert(src.to_owned(), dst.to_owned());
+    /// Binds `new` onto the mount point `old`. Adding to a directory that
+    /// isn't a mount point yet keeps the directory as a member of the union.
+    pub fn bind(&mut self, old: &str, new: &str, flags: BindFlags) {
+        let old = clean_name(old);
+        let new = clean_name(new);
+        let mount = Mount {
+            to: new,
+            create: flags.contains(BindFlags::MCREATE),
+            base: false,
+        };
+
+        if !self.mounts.contains_key(&old) && flags.intersects(BindFlags::MORDER) {
+            if let Ok(_) = self.resolve(&old, 0, Some(&old)) {
+                self.mounts.insert(old.clone(), vec![Mount {
+                    to: old.clone(),
+                    create: false,
+                    base: true,
+                }]);
+            }
+        }
+
+        let members = self.mounts.entry(old).or_insert_with(Vec::new);
+        if flags.contains(BindFlags::MBEFORE) {
+            members.insert(0, mount);
+        } else if flags.contains(BindFlags::MAFTER) {
+            members.push(mount);
+        } else {
+            members.clear();
+            members.push(mount);
+        }
     }
 
-    pub fn open_file(&self, path: &str) -> nine_p::Result<(dev::FileServer, nine_p::Fid)> {
-        if path.starts_with("#") {
-            match path.chars().nth(1) {
-                Some(c) => {
-                    match dev::get_dev_driver(c) {
-                        Some(d) => {
-                            let fid = d.fid_pool().get_fid();
-                            d.server().lock().attach(fid, nine_p::NO_FID, "", "")?;
-                            Ok((d, fid))
-                        },
-                        None => Err(nine_p::DevError::NoSuchFile)
+    /// Removes `new` from the union at `old`, or the whole union if `new`
+    /// is `None`.
+    pub fn unmount(&mut self, old: &str, new: Option<&str>) -> nine_p::Result<()> {
+        let old = clean_name(old);
+        let new = new.map(clean_name);
+        let now_empty = match self.mounts.get_mut(&old) {
+            None => return Err(nine_p::DevError::Str("not mounted".to_owned())),
+            Some(members) => match new {
+                None => true,
+                Some(new) => {
+                    let len = members.len();
+                    members.retain(|m| m.base || m.to != new);
+                    if members.len() == len {
+                        return Err(nine_p::DevError::Str("not mounted".to_owned()));
                     }
-                },
-                None => Err(nine_p::DevError::NoSuchFile)
+                    members.is_empty()
+                }
             }
+        };
+        if now_empty {
+            self.mounts.remove(&old);
+        }
+        Ok(())
+    }
+
+    /// Attaches to the device named by a `#<char><spec>/<rest>` path, passing
+    /// the spec as the attach name, and walks the rest of the path.
+    fn attach_device(&self, path: &str) -> nine_p::Result<dev::Chan> {
+        let (dev, rest) = match path.find('/') {
+            Some(i) => (&path[..i], &path[i..]),
+            None => (path, "")
+        };
+        let mut chars = dev[1..].chars();
+        let root = match chars.next() {
+            Some(c) => {
+                match dev::get_dev_driver(c) {
+                    Some(d) => dev::Chan::attach(&d, "", chars.as_str())?,
+                    None => return Err(nine_p::DevError::NoSuchFile)
+                }
+            },
+            None => return Err(nine_p::DevError::NoSuchFile)
+        };
+        root.walk(&elements(rest))
+    }
+
+    /// Finds the longest mount point that `path` is at or below. `skip` is
+    /// a mount point to pretend isn't there.
+    fn longest_mount(&self, path: &str, skip: Option<&str>) -> Option<(&str, &Vec<Mount>)> {
+        self.mounts.iter()
+            .filter(|(old, _)| Some(old.as_str()) != skip && is_path_prefix(old, path))
+            .max_by_key(|(old, _)| old.len())
+            .map(|(old, members)| (old.as_str(), members))
+    }
+
+    fn resolve(&self, path: &str, depth: usize, skip: Option<&str>) -> nine_p::Result<dev::Chan> {
+        if depth > MAX_BIND_DEPTH {
+            return Err(nine_p::DevError::Str("too many levels of binds".to_owned()));
+        }
+
+        let path = clean_name(path);
+        if path.starts_with("#") {
+            return self.attach_device(&path);
+        }
+        if !path.starts_with('/') {
+            return Err(nine_p::DevError::NoSuchFile);
+        }
+
+        match self.longest_mount(&path, skip) {
+            Some((old, members)) => {
+                let rest = if old == "/" { &path[..] } else { &path[old.len()..] };
+                self.walk_union(members, &elements(rest), depth)
+            }
+            None => Err(nine_p::DevError::NoSuchFile)
+        }
+    }
+
+    fn resolve_member(&self, member: &Mount, depth: usize) -> nine_p::Result<dev::Chan> {
+        if member.base {
+            self.resolve(&member.to, depth + 1, Some(&member.to))
         } else {
-            unimplemented!()
+            self.resolve(&member.to, depth + 1, None)
         }
     }
-}
\ No newline at end of file
+
+    /// Walks `names` through each member of a union in order, returning
+    /// the first member that has them all.
+    fn walk_union(&self, members: &[Mount], names: &[&str], depth: usize) -> nine_p::Result<dev::Chan> {
+        let mut err = nine_p::DevError::NoSuchFile;
+        for member in members {
+            let res = self.resolve_member(member, depth)
+                .and_then(|c| c.walk(names));
+            match res {
+                Ok(c) => return Ok(c),
+                Err(e) => err = e
+            }
+        }
+        Err(err)
+    }
+
+    /// Resolves an absolute or `#X` device path to an unopened channel.
+    pub fn walk(&self, path: &str) -> nine_p::Result<dev::Chan> {
+        self.resolve(path, 0, None)
+    }
+
+    pub fn open_file(&self, path: &str, mode: &nine_p::FileMode) -> nine_p::Result<dev::Chan> {
+        let mut chan = self.walk(path)?;
+        chan.open(mode)?;
+        Ok(chan)
+    }
+
+    /// Lists a directory. At a mount point the entries of every member that
+    /// can be read are merged, with the earlier member winning where names
+    /// clash.
+    pub fn read_dir(&self, path: &str) -> nine_p::Result<Vec<nine_p::dir::Dir>> {
+        let path = clean_name(path);
+        let read_mode = nine_p::FileMode::new(nine_p::FileAccessMode::Read, false, false);
+
+        let members = match self.mounts.get(&path) {
+            Some(m) => m,
+            None => return self.open_file(&path, &read_mode)?.read_dir()
+        };
+
+        let mut out: Vec<nine_p::dir::Dir> = Vec::new();
+        for member in members {
+            let mut chan = match self.resolve_member(member, 0) {
+                Ok(c) => c,
+                Err(_) => continue
+            };
+            if !chan.is_dir() {
+                continue;
+            }
+            // a member that can't be read is left out of the listing
+            let dirs = match chan.open(&read_mode).and_then(|_| chan.read_dir()) {
+                Ok(dirs) => dirs,
+                Err(_) => continue
+            };
+            for dir in dirs {
+                if !out.iter().any(|d| d.name() == dir.name()) {
+                    out.push(dir);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Creates and opens `path`. If its directory is a mount point the file
+    /// goes to the union's first MCREATE member.
+    pub fn create(&self, path: &str, perm: u32, mode: &nine_p::FileMode) -> nine_p::Result<dev::Chan> {
+        let path = clean_name(path);
+        let (dir, name) = match path.rfind('/') {
+            Some(i) if i + 1 < path.len() => (if i == 0 { "/" } else { &path[..i] }, &path[i + 1..]),
+            _ => return Err(nine_p::DevError::Str("bad file name".to_owned()))
+        };
+
+        let mut chan = match self.mounts.get(dir) {
+            Some(members) => match members.iter().find(|m| m.create) {
+                Some(member) => self.resolve_member(member, 0)?,
+                None => return Err(nine_p::DevError::Str("mounted directory forbids creation".to_owned()))
+            },
+            None => self.walk(dir)?
+        };
+        chan.create(name, perm, mode)?;
+        Ok(chan)
+    }
+}
@@ -1,13 +1,20 @@
 use alloc::string::String;
 use alloc::vec::Vec;
-use alloc::vec;
-use byteorder::{LittleEndian, ByteOrder};
 use alloc::borrow::ToOwned;
+use super::qidpool::Qid;
+use super::{wire, DevError, Result};
 
+pub const DMDIR: u32 = 0x8000_0000;
+pub const DMAPPEND: u32 = 0x4000_0000;
+pub const DMEXCL: u32 = 0x2000_0000;
+pub const DMAUTH: u32 = 0x0800_0000;
+pub const DMTMP: u32 = 0x0400_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Dir {
     dir_type: u16,
     dev: u32,
-    qid: super::qidpool::Qid,
+    qid: Qid,
     mode: u32,
     atime: u32,
     mtime: u32,
@@ -18,26 +25,8 @@ pub struct Dir {
     muid: String,
 }
 
-fn write_u16(vec: &mut Vec<u8>, n: u16) {
-    let mut buf = [0; 2];
-    LittleEndian::write_u16(&mut buf, n);
-    vec.extend_from_slice(&buf);
-}
-
-fn write_u32(vec: &mut Vec<u8>, n: u32) {
-    let mut buf = [0; 4];
-    LittleEndian::write_u32(&mut buf, n);
-    vec.extend_from_slice(&buf);
-}
-
-fn write_u64(vec: &mut Vec<u8>, n: u64) {
-    let mut buf = [0; 8];
-    LittleEndian::write_u64(&mut buf, n);
-    vec.extend_from_slice(&buf);
-}
-
 impl Dir {
-    pub fn new(dir_type: u16, dev: u32, qid: &super::qidpool::Qid, mode: u32, atime: u32, mtime: u32, length: u64, name: &str, uid: &str, gid: &str, muid: &str) -> Self {
+    pub fn new(dir_type: u16, dev: u32, qid: &Qid, mode: u32, atime: u32, mtime: u32, length: u64, name: &str, uid: &str, gid: &str, muid: &str) -> Self {
         Self {
             dir_type,
             dev,
@@ -53,36 +42,198 @@ impl Dir {
         }
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
-        let name_len = self.name.len() as u16;
-        let uid_len = self.uid.len() as u16;
-        let gid_len = self.gid.len() as u16;
-        let muid_len = self.muid.len() as u16;
-        let len = 48 + name_len + uid_len + gid_len + muid_len;
+    /// A stat entry with every field set to "don't touch", the starting
+    /// point for building a wstat request.
+    pub fn null() -> Self {
+        Self {
+            dir_type: !0,
+            dev: !0,
+            qid: Qid::from_raw_type(!0, !0, !0),
+            mode: !0,
+            atime: !0,
+            mtime: !0,
+            length: !0,
+            name: String::new(),
+            uid: String::new(),
+            gid: String::new(),
+            muid: String::new()
+        }
+    }
+
+    /// Whether every field of a wstat request is "don't touch", which asks
+    /// the server only to commit the file to stable storage.
+    pub fn is_null(&self) -> bool {
+        self.dir_type == !0 && self.dev == !0 && self.qid.raw_type() == !0 &&
+            self.qid.version() == !0 && self.qid.path() == !0 && self.mode == !0 &&
+            self.atime == !0 && self.mtime == !0 && self.length == !0 &&
+            self.name.is_empty() && self.uid.is_empty() && self.gid.is_empty() && self.muid.is_empty()
+    }
+
+    /// Applies a wstat request to this entry. Only name, length, mode, mtime
+    /// and gid may change; every other field has to be "don't touch". Nothing
+    /// is modified unless the whole request is acceptable.
+    pub fn apply_wstat(&mut self, wstat: &Dir) -> Result<()> {
+        if wstat.dir_type != !0 || wstat.dev != !0 || wstat.atime != !0 ||
+            !wstat.uid.is_empty() || !wstat.muid.is_empty() {
+            return Err(DevError::PermissionDenied);
+        }
+        if wstat.qid.raw_type() != !0 || wstat.qid.version() != !0 || wstat.qid.path() != !0 {
+            return Err(DevError::PermissionDenied);
+        }
+        if wstat.mode != !0 && (wstat.mode & DMDIR) != (self.mode & DMDIR) {
+            return Err(DevError::Str("can't change directory bit".to_owned()));
+        }
+        if wstat.length != !0 && wstat.length != self.length && self.mode & DMDIR != 0 {
+            return Err(DevError::Str("can't change length of a directory".to_owned()));
+        }
+        if wstat.name.contains('/') || wstat.name == "." || wstat.name == ".." {
+            return Err(DevError::Str("bad file name".to_owned()));
+        }
 
-        let mut out = vec![];
+        if !wstat.name.is_empty() {
+            self.name = wstat.name.clone();
+        }
+        if wstat.length != !0 {
+            self.length = wstat.length;
+        }
+        if wstat.mode != !0 {
+            self.mode = wstat.mode;
+        }
+        if wstat.mtime != !0 {
+            self.mtime = wstat.mtime;
+        }
+        if !wstat.gid.is_empty() {
+            self.gid = wstat.gid.clone();
+        }
+        Ok(())
+    }
+
+    pub fn dir_type(&self) -> u16 {
+        self.dir_type
+    }
+
+    pub fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    pub fn qid(&self) -> Qid {
+        self.qid
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn atime(&self) -> u32 {
+        self.atime
+    }
+
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
 
-        write_u16(&mut out, len);
-        write_u16(&mut out, self.dir_type);
-        write_u32(&mut out, self.dev);
-        out.extend_from_slice(&self.qid.as_bytes());
-        write_u32(&mut out, self.mode);
-        write_u32(&mut out, self.atime);
-        write_u32(&mut out, self.mtime);
-        write_u64(&mut out, self.length);
+    pub fn length(&self) -> u64 {
+        self.length
+    }
 
-        write_u16(&mut out, name_len);
-        out.extend_from_slice(&self.name.clone().into_bytes());
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        write_u16(&mut out, uid_len);
-        out.extend_from_slice(&self.uid.clone().into_bytes());
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
 
-        write_u16(&mut out, gid_len);
-        out.extend_from_slice(&self.gid.clone().into_bytes());
+    pub fn gid(&self) -> &str {
+        &self.gid
+    }
 
-        write_u16(&mut out, muid_len);
-        out.extend_from_slice(&self.muid.clone().into_bytes());
+    pub fn muid(&self) -> &str {
+        &self.muid
+    }
 
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(wire::stat_size(self));
+        wire::put_stat(&mut out, self);
         out
     }
-}
\ No newline at end of file
+
+    /// Parses a single stat entry, as returned by `as_bytes`.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut d = wire::Decoder::new(buf);
+        let dir = d.get_stat()?;
+        if !d.is_empty() {
+            return Err(DevError::BadMessage);
+        }
+        Ok(dir)
+    }
+
+    /// Parses the back-to-back stat entries returned by reading a directory.
+    pub fn list_from_bytes(buf: &[u8]) -> Result<Vec<Self>> {
+        let mut d = wire::Decoder::new(buf);
+        let mut out = Vec::new();
+        while !d.is_empty() {
+            out.push(d.get_stat()?);
+        }
+        Ok(out)
+    }
+}
+
+/// Serves an already collected directory listing, packing as many whole
+/// entries into each read as will fit.
+#[derive(Debug)]
+pub struct ListReader {
+    entries: Vec<Dir>,
+    pos: usize,
+}
+
+impl ListReader {
+    pub fn new(entries: Vec<Dir>) -> Self {
+        Self {
+            entries,
+            pos: 0
+        }
+    }
+}
+
+impl super::RWC for ListReader {}
+
+impl super::Read for ListReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.entries.len() {
+            return Err(DevError::EOF);
+        }
+
+        let mut n = 0;
+        while let Some(dir) = self.entries.get(self.pos) {
+            let dir_b = dir.as_bytes();
+            if n + dir_b.len() > buf.len() {
+                break;
+            }
+            buf[n..n + dir_b.len()].copy_from_slice(&dir_b);
+            n += dir_b.len();
+            self.pos += 1;
+        }
+
+        if n == 0 {
+            return Err(DevError::SmallRead);
+        }
+        Ok(n)
+    }
+}
+
+impl super::Write for ListReader {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(DevError::PermissionDenied)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl super::Close for ListReader {
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
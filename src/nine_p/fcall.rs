@@ -0,0 +1,489 @@
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::string::String;
+use byteorder::{LittleEndian, ByteOrder};
+use super::qidpool::Qid;
+use super::wire::{self, Decoder};
+use super::{DevError, Fid, Result};
+
+pub type Tag = u16;
+pub const NO_TAG: Tag = 0xffff;
+
+pub const VERSION: &str = "9P2000";
+pub const MAX_WALK_ELEMENTS: usize = 16;
+
+/// size[4] type[1] tag[2]
+pub const HEADER_SIZE: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FcallType {
+    Tversion = 100,
+    Rversion = 101,
+    Tauth = 102,
+    Rauth = 103,
+    Tattach = 104,
+    Rattach = 105,
+    Rerror = 107,
+    Tflush = 108,
+    Rflush = 109,
+    Twalk = 110,
+    Rwalk = 111,
+    Topen = 112,
+    Ropen = 113,
+    Tcreate = 114,
+    Rcreate = 115,
+    Tread = 116,
+    Rread = 117,
+    Twrite = 118,
+    Rwrite = 119,
+    Tclunk = 120,
+    Rclunk = 121,
+    Tremove = 122,
+    Rremove = 123,
+    Tstat = 124,
+    Rstat = 125,
+    Twstat = 126,
+    Rwstat = 127,
+}
+
+impl FcallType {
+    fn from_u8(n: u8) -> Option<FcallType> {
+        Some(match n {
+            100 => FcallType::Tversion,
+            101 => FcallType::Rversion,
+            102 => FcallType::Tauth,
+            103 => FcallType::Rauth,
+            104 => FcallType::Tattach,
+            105 => FcallType::Rattach,
+            107 => FcallType::Rerror,
+            108 => FcallType::Tflush,
+            109 => FcallType::Rflush,
+            110 => FcallType::Twalk,
+            111 => FcallType::Rwalk,
+            112 => FcallType::Topen,
+            113 => FcallType::Ropen,
+            114 => FcallType::Tcreate,
+            115 => FcallType::Rcreate,
+            116 => FcallType::Tread,
+            117 => FcallType::Rread,
+            118 => FcallType::Twrite,
+            119 => FcallType::Rwrite,
+            120 => FcallType::Tclunk,
+            121 => FcallType::Rclunk,
+            122 => FcallType::Tremove,
+            123 => FcallType::Rremove,
+            124 => FcallType::Tstat,
+            125 => FcallType::Rstat,
+            126 => FcallType::Twstat,
+            127 => FcallType::Rwstat,
+            _ => return None
+        })
+    }
+}
+
+/// The body of a 9P2000 message. Stat entries are kept as raw bytes in the
+/// same layout `Dir::as_bytes` produces, like `Fcall.stat` in Plan 9's fcall.h.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Msg {
+    Tversion { msize: u32, version: String },
+    Rversion { msize: u32, version: String },
+    Tauth { afid: Fid, uname: String, aname: String },
+    Rauth { aqid: Qid },
+    Tattach { fid: Fid, afid: Fid, uname: String, aname: String },
+    Rattach { qid: Qid },
+    Rerror { ename: String },
+    Tflush { oldtag: Tag },
+    Rflush,
+    Twalk { fid: Fid, newfid: Fid, wnames: Vec<String> },
+    Rwalk { wqids: Vec<Qid> },
+    Topen { fid: Fid, mode: u8 },
+    Ropen { qid: Qid, iounit: u32 },
+    Tcreate { fid: Fid, name: String, perm: u32, mode: u8 },
+    Rcreate { qid: Qid, iounit: u32 },
+    Tread { fid: Fid, offset: u64, count: u32 },
+    Rread { data: Vec<u8> },
+    Twrite { fid: Fid, offset: u64, data: Vec<u8> },
+    Rwrite { count: u32 },
+    Tclunk { fid: Fid },
+    Rclunk,
+    Tremove { fid: Fid },
+    Rremove,
+    Tstat { fid: Fid },
+    Rstat { stat: Vec<u8> },
+    Twstat { fid: Fid, stat: Vec<u8> },
+    Rwstat,
+}
+
+impl Msg {
+    pub fn msg_type(&self) -> FcallType {
+        match self {
+            Msg::Tversion { .. } => FcallType::Tversion,
+            Msg::Rversion { .. } => FcallType::Rversion,
+            Msg::Tauth { .. } => FcallType::Tauth,
+            Msg::Rauth { .. } => FcallType::Rauth,
+            Msg::Tattach { .. } => FcallType::Tattach,
+            Msg::Rattach { .. } => FcallType::Rattach,
+            Msg::Rerror { .. } => FcallType::Rerror,
+            Msg::Tflush { .. } => FcallType::Tflush,
+            Msg::Rflush => FcallType::Rflush,
+            Msg::Twalk { .. } => FcallType::Twalk,
+            Msg::Rwalk { .. } => FcallType::Rwalk,
+            Msg::Topen { .. } => FcallType::Topen,
+            Msg::Ropen { .. } => FcallType::Ropen,
+            Msg::Tcreate { .. } => FcallType::Tcreate,
+            Msg::Rcreate { .. } => FcallType::Rcreate,
+            Msg::Tread { .. } => FcallType::Tread,
+            Msg::Rread { .. } => FcallType::Rread,
+            Msg::Twrite { .. } => FcallType::Twrite,
+            Msg::Rwrite { .. } => FcallType::Rwrite,
+            Msg::Tclunk { .. } => FcallType::Tclunk,
+            Msg::Rclunk => FcallType::Rclunk,
+            Msg::Tremove { .. } => FcallType::Tremove,
+            Msg::Rremove => FcallType::Rremove,
+            Msg::Tstat { .. } => FcallType::Tstat,
+            Msg::Rstat { .. } => FcallType::Rstat,
+            Msg::Twstat { .. } => FcallType::Twstat,
+            Msg::Rwstat => FcallType::Rwstat,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fcall {
+    tag: Tag,
+    msg: Msg,
+}
+
+fn put_stat_bytes(buf: &mut Vec<u8>, stat: &[u8]) {
+    wire::put_u16(buf, stat.len() as u16);
+    buf.extend_from_slice(stat);
+}
+
+fn read_stat(r: &mut Decoder) -> Result<Vec<u8>> {
+    let len = r.get_u16()? as usize;
+    Ok(r.get_bytes(len)?.to_vec())
+}
+
+/// Returns the size of the message at the start of `buf`, as given by its
+/// size prefix, or `None` if not even the prefix has been received yet.
+pub fn frame_size(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 4 {
+        None
+    } else {
+        Some(LittleEndian::read_u32(&buf[..4]) as usize)
+    }
+}
+
+impl Fcall {
+    pub fn new(tag: Tag, msg: Msg) -> Self {
+        Self {
+            tag,
+            msg
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    pub fn msg(&self) -> &Msg {
+        &self.msg
+    }
+
+    pub fn into_msg(self) -> Msg {
+        self.msg
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut out = vec![0; 4];
+
+        wire::put_u8(&mut out, self.msg.msg_type() as u8);
+        wire::put_u16(&mut out, self.tag);
+
+        match &self.msg {
+            Msg::Tversion { msize, version } | Msg::Rversion { msize, version } => {
+                wire::put_u32(&mut out, *msize);
+                wire::put_string(&mut out, version);
+            }
+            Msg::Tauth { afid, uname, aname } => {
+                wire::put_u32(&mut out, *afid);
+                wire::put_string(&mut out, uname);
+                wire::put_string(&mut out, aname);
+            }
+            Msg::Rauth { aqid } => wire::put_qid(&mut out, aqid),
+            Msg::Tattach { fid, afid, uname, aname } => {
+                wire::put_u32(&mut out, *fid);
+                wire::put_u32(&mut out, *afid);
+                wire::put_string(&mut out, uname);
+                wire::put_string(&mut out, aname);
+            }
+            Msg::Rattach { qid } => wire::put_qid(&mut out, qid),
+            Msg::Rerror { ename } => wire::put_string(&mut out, ename),
+            Msg::Tflush { oldtag } => wire::put_u16(&mut out, *oldtag),
+            Msg::Twalk { fid, newfid, wnames } => {
+                wire::put_u32(&mut out, *fid);
+                wire::put_u32(&mut out, *newfid);
+                wire::put_u16(&mut out, wnames.len() as u16);
+                for name in wnames {
+                    wire::put_string(&mut out, name);
+                }
+            }
+            Msg::Rwalk { wqids } => {
+                wire::put_u16(&mut out, wqids.len() as u16);
+                for qid in wqids {
+                    wire::put_qid(&mut out, qid);
+                }
+            }
+            Msg::Topen { fid, mode } => {
+                wire::put_u32(&mut out, *fid);
+                wire::put_u8(&mut out, *mode);
+            }
+            Msg::Ropen { qid, iounit } | Msg::Rcreate { qid, iounit } => {
+                wire::put_qid(&mut out, qid);
+                wire::put_u32(&mut out, *iounit);
+            }
+            Msg::Tcreate { fid, name, perm, mode } => {
+                wire::put_u32(&mut out, *fid);
+                wire::put_string(&mut out, name);
+                wire::put_u32(&mut out, *perm);
+                wire::put_u8(&mut out, *mode);
+            }
+            Msg::Tread { fid, offset, count } => {
+                wire::put_u32(&mut out, *fid);
+                wire::put_u64(&mut out, *offset);
+                wire::put_u32(&mut out, *count);
+            }
+            Msg::Rread { data } => {
+                wire::put_u32(&mut out, data.len() as u32);
+                out.extend_from_slice(data);
+            }
+            Msg::Twrite { fid, offset, data } => {
+                wire::put_u32(&mut out, *fid);
+                wire::put_u64(&mut out, *offset);
+                wire::put_u32(&mut out, data.len() as u32);
+                out.extend_from_slice(data);
+            }
+            Msg::Rwrite { count } => wire::put_u32(&mut out, *count),
+            Msg::Tclunk { fid } | Msg::Tremove { fid } | Msg::Tstat { fid } => wire::put_u32(&mut out, *fid),
+            Msg::Rstat { stat } => put_stat_bytes(&mut out, stat),
+            Msg::Twstat { fid, stat } => {
+                wire::put_u32(&mut out, *fid);
+                put_stat_bytes(&mut out, stat);
+            }
+            Msg::Rflush | Msg::Rclunk | Msg::Rremove | Msg::Rwstat => {}
+        }
+
+        let len = out.len() as u32;
+        LittleEndian::write_u32(&mut out[..4], len);
+        out
+    }
+
+    /// Decodes exactly one message. `buf` must hold the whole frame, size
+    /// prefix included, and nothing past it.
+    pub fn from_bytes(buf: &[u8]) -> Result<Fcall> {
+        match frame_size(buf) {
+            Some(size) if size == buf.len() && size >= HEADER_SIZE => {}
+            _ => return Err(DevError::BadMessage)
+        }
+
+        let mut r = Decoder::new(&buf[4..]);
+        let msg_type = match FcallType::from_u8(r.get_u8()?) {
+            Some(t) => t,
+            None => return Err(DevError::BadMessage)
+        };
+        let tag = r.get_u16()?;
+
+        let msg = match msg_type {
+            FcallType::Tversion => Msg::Tversion { msize: r.get_u32()?, version: r.get_string()? },
+            FcallType::Rversion => Msg::Rversion { msize: r.get_u32()?, version: r.get_string()? },
+            FcallType::Tauth => Msg::Tauth { afid: r.get_u32()?, uname: r.get_string()?, aname: r.get_string()? },
+            FcallType::Rauth => Msg::Rauth { aqid: r.get_qid()? },
+            FcallType::Tattach => Msg::Tattach {
+                fid: r.get_u32()?,
+                afid: r.get_u32()?,
+                uname: r.get_string()?,
+                aname: r.get_string()?
+            },
+            FcallType::Rattach => Msg::Rattach { qid: r.get_qid()? },
+            FcallType::Rerror => Msg::Rerror { ename: r.get_string()? },
+            FcallType::Tflush => Msg::Tflush { oldtag: r.get_u16()? },
+            FcallType::Rflush => Msg::Rflush,
+            FcallType::Twalk => {
+                let fid = r.get_u32()?;
+                let newfid = r.get_u32()?;
+                let nwname = r.get_u16()? as usize;
+                if nwname > MAX_WALK_ELEMENTS {
+                    return Err(DevError::BadMessage);
+                }
+                let mut wnames = Vec::with_capacity(nwname);
+                for _ in 0..nwname {
+                    wnames.push(r.get_string()?);
+                }
+                Msg::Twalk { fid, newfid, wnames }
+            }
+            FcallType::Rwalk => {
+                let nwqid = r.get_u16()? as usize;
+                if nwqid > MAX_WALK_ELEMENTS {
+                    return Err(DevError::BadMessage);
+                }
+                let mut wqids = Vec::with_capacity(nwqid);
+                for _ in 0..nwqid {
+                    wqids.push(r.get_qid()?);
+                }
+                Msg::Rwalk { wqids }
+            }
+            FcallType::Topen => Msg::Topen { fid: r.get_u32()?, mode: r.get_u8()? },
+            FcallType::Ropen => Msg::Ropen { qid: r.get_qid()?, iounit: r.get_u32()? },
+            FcallType::Tcreate => Msg::Tcreate {
+                fid: r.get_u32()?,
+                name: r.get_string()?,
+                perm: r.get_u32()?,
+                mode: r.get_u8()?
+            },
+            FcallType::Rcreate => Msg::Rcreate { qid: r.get_qid()?, iounit: r.get_u32()? },
+            FcallType::Tread => Msg::Tread { fid: r.get_u32()?, offset: r.get_u64()?, count: r.get_u32()? },
+            FcallType::Rread => {
+                let count = r.get_u32()? as usize;
+                Msg::Rread { data: r.get_bytes(count)?.to_vec() }
+            }
+            FcallType::Twrite => {
+                let fid = r.get_u32()?;
+                let offset = r.get_u64()?;
+                let count = r.get_u32()? as usize;
+                Msg::Twrite { fid, offset, data: r.get_bytes(count)?.to_vec() }
+            }
+            FcallType::Rwrite => Msg::Rwrite { count: r.get_u32()? },
+            FcallType::Tclunk => Msg::Tclunk { fid: r.get_u32()? },
+            FcallType::Rclunk => Msg::Rclunk,
+            FcallType::Tremove => Msg::Tremove { fid: r.get_u32()? },
+            FcallType::Rremove => Msg::Rremove,
+            FcallType::Tstat => Msg::Tstat { fid: r.get_u32()? },
+            FcallType::Rstat => Msg::Rstat { stat: read_stat(&mut r)? },
+            FcallType::Twstat => Msg::Twstat { fid: r.get_u32()?, stat: read_stat(&mut r)? },
+            FcallType::Rwstat => Msg::Rwstat,
+        };
+
+        if !r.is_empty() {
+            return Err(DevError::BadMessage);
+        }
+
+        Ok(Fcall::new(tag, msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::qidpool::QidType;
+
+    fn qid(path: u64) -> Qid {
+        Qid::new(QidType::DIRECTORY, 7, path)
+    }
+
+    fn round_trip(msg: Msg) {
+        let fcall = Fcall::new(0x1234, msg);
+        let bytes = fcall.as_bytes();
+        assert_eq!(frame_size(&bytes), Some(bytes.len()));
+        assert_eq!(Fcall::from_bytes(&bytes).unwrap(), fcall);
+    }
+
+    /// A valid frame with its size prefix rewritten to match its length.
+    fn resized(mut bytes: Vec<u8>) -> Vec<u8> {
+        let len = bytes.len() as u32;
+        LittleEndian::write_u32(&mut bytes[..4], len);
+        bytes
+    }
+
+    #[test]
+    fn version_and_auth() {
+        round_trip(Msg::Tversion { msize: 8192, version: String::from(VERSION) });
+        round_trip(Msg::Rversion { msize: 4096, version: String::from("unknown") });
+        round_trip(Msg::Tauth { afid: 1, uname: String::from("glenda"), aname: String::new() });
+        round_trip(Msg::Rauth { aqid: Qid::new(QidType::AUTHENTICATION, 0, 3) });
+        round_trip(Msg::Tattach { fid: 2, afid: !0, uname: String::from("glenda"), aname: String::from("main") });
+        round_trip(Msg::Rattach { qid: qid(0) });
+        round_trip(Msg::Rerror { ename: String::from("file does not exist") });
+        round_trip(Msg::Tflush { oldtag: 9 });
+        round_trip(Msg::Rflush);
+    }
+
+    #[test]
+    fn walk() {
+        let wnames = (0..MAX_WALK_ELEMENTS).map(|i| if i % 2 == 0 { String::from("usr") } else { String::from("..") });
+        round_trip(Msg::Twalk { fid: 1, newfid: 2, wnames: wnames.collect() });
+        round_trip(Msg::Twalk { fid: 1, newfid: 1, wnames: Vec::new() });
+        round_trip(Msg::Rwalk { wqids: (0..MAX_WALK_ELEMENTS as u64).map(qid).collect() });
+        round_trip(Msg::Rwalk { wqids: Vec::new() });
+    }
+
+    #[test]
+    fn open_create_read_write() {
+        round_trip(Msg::Topen { fid: 1, mode: 0x12 });
+        round_trip(Msg::Ropen { qid: qid(4), iounit: 8168 });
+        round_trip(Msg::Tcreate { fid: 1, name: String::from("new"), perm: 0o644, mode: 1 });
+        round_trip(Msg::Rcreate { qid: Qid::new(QidType::FILE, 0, 5), iounit: 0 });
+        round_trip(Msg::Tread { fid: 1, offset: !0 - 1, count: 8168 });
+        round_trip(Msg::Rread { data: vec![0, 1, 2, 0xff] });
+        round_trip(Msg::Rread { data: Vec::new() });
+        round_trip(Msg::Twrite { fid: 1, offset: 1 << 40, data: vec![b'x'; 300] });
+        round_trip(Msg::Rwrite { count: 300 });
+    }
+
+    #[test]
+    fn clunk_remove_stat() {
+        round_trip(Msg::Tclunk { fid: 1 });
+        round_trip(Msg::Rclunk);
+        round_trip(Msg::Tremove { fid: 1 });
+        round_trip(Msg::Rremove);
+        round_trip(Msg::Tstat { fid: 1 });
+        round_trip(Msg::Rstat { stat: vec![0xaa; 60] });
+        round_trip(Msg::Twstat { fid: 1, stat: vec![0xff; 49] });
+        round_trip(Msg::Rwstat);
+    }
+
+    #[test]
+    fn tversion_bytes() {
+        let fcall = Fcall::new(NO_TAG, Msg::Tversion { msize: 8192, version: String::from(VERSION) });
+        let want = b"\x13\x00\x00\x00\x64\xff\xff\x00\x20\x00\x00\x06\x009P2000";
+        assert_eq!(fcall.as_bytes(), want.to_vec());
+    }
+
+    #[test]
+    fn truncated_frames() {
+        let bytes = Fcall::new(1, Msg::Twrite { fid: 1, offset: 0, data: vec![1, 2, 3] }).as_bytes();
+        assert!(Fcall::from_bytes(&[]).is_err());
+        assert!(Fcall::from_bytes(&bytes[..3]).is_err());
+        assert!(Fcall::from_bytes(&bytes[..HEADER_SIZE]).is_err());
+        assert!(Fcall::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        for len in 4..bytes.len() {
+            assert!(Fcall::from_bytes(&resized(bytes[..len].to_vec())).is_err());
+        }
+        assert!(Fcall::from_bytes(&resized(vec![0, 0, 0, 0, 120, 1])).is_err());
+    }
+
+    #[test]
+    fn oversized_frames() {
+        let mut bytes = Fcall::new(1, Msg::Tclunk { fid: 1 }).as_bytes();
+        bytes.push(0);
+        assert!(Fcall::from_bytes(&bytes).is_err());
+        assert!(Fcall::from_bytes(&resized(bytes)).is_err());
+
+        let mut bytes = Fcall::new(1, Msg::Rread { data: vec![1, 2, 3] }).as_bytes();
+        LittleEndian::write_u32(&mut bytes[HEADER_SIZE..], 4);
+        assert!(Fcall::from_bytes(&bytes).is_err());
+
+        let wnames = vec![String::from("a"); MAX_WALK_ELEMENTS + 1];
+        let bytes = Fcall::new(1, Msg::Twalk { fid: 1, newfid: 2, wnames }).as_bytes();
+        assert!(Fcall::from_bytes(&bytes).is_err());
+        let bytes = Fcall::new(1, Msg::Rwalk { wqids: vec![qid(0); MAX_WALK_ELEMENTS + 1] }).as_bytes();
+        assert!(Fcall::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn unknown_type() {
+        let mut bytes = Fcall::new(1, Msg::Rflush).as_bytes();
+        bytes[4] = 106;
+        assert!(Fcall::from_bytes(&bytes).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 pub mod qidpool;
 pub mod dir;
+pub mod fcall;
+pub mod wire;
 
 use alloc::vec::Vec;
 use alloc::vec;
@@ -27,13 +29,14 @@ pub enum DevError {
     NoSuchFile,
     NotADir,
     FileOpen,
+    BadMessage,
     Str(String),
 }
 
 pub type Result<T> = core::result::Result<T, DevError>;
 
 impl DevError {
-    fn description(&self) -> String {
+    pub fn description(&self) -> String {
         match &*self {
             DevError::EOF => "End of file".to_string(),
             DevError::AuthNotNeeded => "Authentication not required".to_string(),
@@ -45,6 +48,7 @@ impl DevError {
             DevError::NoSuchFile => "No such file or directory".to_string(),
             DevError::NotADir => "Not a directory".to_string(),
             DevError::FileOpen => "File is open".to_string(),
+            DevError::BadMessage => "Malformed 9P message".to_string(),
             DevError::Str(string) => string.to_owned(),
         }
     }
@@ -72,6 +76,7 @@ impl Session {
 struct _File<'a> {
     name: String,
     auth: bool,
+    access: Option<FileAccessMode>,
     rwc: Option<Arc<RwLock<Box<(dyn FileRWC + 'a)>>>>
 }
 
@@ -83,6 +88,7 @@ impl<'a> File<'a> {
         Self(Arc::new(RwLock::new(_File {
             name: name.to_owned(),
             auth,
+            access: None,
             rwc: match rwc {
                 None => None,
                 Some(rwc) => Some(Arc::new(RwLock::new(rwc)))
@@ -105,9 +111,18 @@ impl<'a> File<'a> {
     pub fn set_rwc(&self, rwc: Box<(dyn FileRWC + 'a)>) {
         self.0.write().rwc = Some(Arc::new(RwLock::new(rwc)));
     }
+
+    /// The access mode the file was opened with, `None` until it is opened.
+    pub fn access(&self) -> Option<FileAccessMode> {
+        self.0.read().access
+    }
+
+    pub fn set_access(&self, access: FileAccessMode) {
+        self.0.write().access = Some(access);
+    }
 }
 
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum FileAccessMode {
     Read = 0,
     Write = 1,
@@ -135,7 +150,34 @@ impl FileMode {
     }
 }
 
+pub const OTRUNC: u8 = 0x10;
+pub const ORCLOSE: u8 = 0x40;
+
 impl FileMode {
+    pub fn from_bits(mode: u8) -> Result<Self> {
+        let access = match mode & 3 {
+            0 => FileAccessMode::Read,
+            1 => FileAccessMode::Write,
+            2 => FileAccessMode::ReadWrite,
+            _ => FileAccessMode::Execute
+        };
+        if mode & !(3 | OTRUNC | ORCLOSE) != 0 {
+            return Err(DevError::BadMessage);
+        }
+        Ok(Self::new(access, mode & OTRUNC != 0, mode & ORCLOSE != 0))
+    }
+
+    pub fn bits(&self) -> u8 {
+        let mut mode = self.access as u8;
+        if self.truncate {
+            mode |= OTRUNC;
+        }
+        if self.remove_on_close {
+            mode |= ORCLOSE;
+        }
+        mode
+    }
+
     pub fn new(access: FileAccessMode, truncate: bool, remove_on_close: bool) -> Self {
         Self {
             access,
@@ -167,7 +209,7 @@ pub trait RWSC: RWC + Seek {}
 
 pub trait FileRWC: Debug + Sync + Send {
     fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize>;
-    fn write_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize>;
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> Result<usize>;
 }
 
 impl FileRWC for dyn RWSC {
@@ -176,7 +218,7 @@ impl FileRWC for dyn RWSC {
         self.read(buf)
     }
 
-    fn write_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> Result<usize> {
         self.seek(pos)?;
         self.write(buf)
     }
@@ -209,7 +251,7 @@ impl FileRWC for RWCWrapper<'_> {
         Ok(n as usize)
     }
 
-    fn write_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> Result<usize> {
         if self.offset != pos {
             return Err(DevError::NoSeek);
         }
@@ -229,11 +271,11 @@ impl FileRWC for &mut [u8] {
 
         let read_len = min(buf.len(), self.len() - pos);
 
-        buf[..read_len].copy_from_slice(&self[pos..read_len]);
+        buf[..read_len].copy_from_slice(&self[pos..pos + read_len]);
         Ok(read_len)
     }
 
-    fn write_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> Result<usize> {
         let pos = pos as usize;
         if pos >= self.len() {
             return Err(DevError::EOF);
@@ -241,7 +283,7 @@ impl FileRWC for &mut [u8] {
 
         let write_len = min(buf.len(), self.len() - pos);
 
-        self[pos..write_len].copy_from_slice(&buf[..write_len]);
+        self[pos..pos + write_len].copy_from_slice(&buf[..write_len]);
         Ok(write_len)
     }
 }
@@ -255,11 +297,11 @@ impl FileRWC for &[u8] {
 
         let read_len = min(buf.len(), self.len() - pos);
 
-        buf[..read_len].copy_from_slice(&self[pos..read_len]);
+        buf[..read_len].copy_from_slice(&self[pos..pos + read_len]);
         Ok(read_len)
     }
 
-    fn write_at(&mut self, _pos: u64, _buf: &mut [u8]) -> Result<usize> {
+    fn write_at(&mut self, _pos: u64, _buf: &[u8]) -> Result<usize> {
         Err(DevError::PermissionDenied)
     }
 }
@@ -278,26 +320,50 @@ pub trait NinePServer: Sync + Send {
     fn clunk(&mut self, fid: Fid) -> Result<()>;
 
     fn open(&mut self, fid: Fid, mode: &FileMode) -> Result<(qidpool::Qid, u32)>;
-    fn create(&self, _fid: Fid, _name: &str, _perm: u32, _mode: &FileMode) -> Result<(qidpool::Qid, u32)> {
+    fn create(&mut self, _fid: Fid, _name: &str, _perm: u32, _mode: &FileMode) -> Result<(qidpool::Qid, u32)> {
         Err(DevError::PermissionDenied)
     }
 
     fn walk(&mut self, fid: Fid, new_fid: Fid, names: &[&str]) -> Result<Vec<qidpool::Qid>>;
 
     fn read(&mut self, fid: Fid, offset: u64, count: usize) -> Result<Vec<u8>>;
-    fn write(&self) -> Result<()> {
+
+    /// The open file behind `fid` if reading it can wait on something
+    /// outside the server, such as console input. Reads of it are then
+    /// made without the server locked, so it stays usable meanwhile.
+    fn blocking_file(&mut self, _fid: Fid) -> Option<File<'static>> {
+        None
+    }
+
+    /// A handle to make every request through instead, without the server
+    /// locked, for a server whose requests can all wait on something
+    /// outside it, such as a remote file server. Such a server keeps its
+    /// state behind locks of its own.
+    fn unlocked(&self) -> Option<Box<dyn NinePServer>> {
+        None
+    }
+
+    fn write(&mut self, _fid: Fid, _offset: u64, _data: &[u8]) -> Result<usize> {
         Err(DevError::PermissionDenied)
     }
 
     fn remove(&mut self, fid: Fid) -> Result<()>;
 
-    fn stat(&self) -> Result<()>;
-    fn wstat(&self) -> Result<()> {
-        Err(DevError::PermissionDenied)
+    fn stat(&mut self, fid: Fid) -> Result<dir::Dir>;
+    fn wstat(&mut self, _fid: Fid, dir: &dir::Dir) -> Result<()> {
+        if dir.is_null() {
+            Ok(())
+        } else {
+            Err(DevError::PermissionDenied)
+        }
     }
 }
 
 pub fn default_read(file: &mut File, offset: u64, count: usize) -> Result<Vec<u8>> {
+    if file.access() == Some(FileAccessMode::Write) {
+        return Err(DevError::PermissionDenied);
+    }
+
     match file.rwc() {
         None => Err(DevError::Str(format!("File {} not open for reading", file.name()))),
         Some(rwc) => {
@@ -308,4 +374,17 @@ pub fn default_read(file: &mut File, offset: u64, count: usize) -> Result<Vec<u8
             Ok(buf)
         }
     }
+}
+
+pub fn default_write(file: &mut File, offset: u64, data: &[u8]) -> Result<usize> {
+    match file.access() {
+        Some(FileAccessMode::Write) | Some(FileAccessMode::ReadWrite) => {}
+        Some(_) => return Err(DevError::PermissionDenied),
+        None => return Err(DevError::Str(format!("File {} not open for writing", file.name())))
+    }
+
+    match file.rwc() {
+        None => Err(DevError::Str(format!("File {} not open for writing", file.name()))),
+        Some(rwc) => rwc.write().write_at(offset, data)
+    }
 }
\ No newline at end of file
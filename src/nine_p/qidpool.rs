@@ -1,21 +1,27 @@
 use alloc::collections;
 use alloc::borrow::ToOwned;
-use byteorder::{NetworkEndian, ByteOrder};
+use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::sync::Arc;
 use spin::RwLock;
 
 pub type QidRaw = [u8; 13];
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Qid {
-    qid_type: QidType,
+    /// The type byte as sent, which may have bits QidType doesn't know,
+    /// such as the all-ones "don't touch" of a wstat.
+    qid_type: u8,
     version: u32,
     path: u64,
 }
 
 impl Qid {
     pub fn new(qid_type: QidType, version: u32, path: u64) -> Self {
+        Self::from_raw_type(qid_type.bits(), version, path)
+    }
+
+    pub fn from_raw_type(qid_type: u8, version: u32, path: u64) -> Self {
         Self {
             qid_type,
             version,
@@ -24,16 +30,32 @@ impl Qid {
     }
 
     pub fn as_bytes(&self) -> QidRaw {
+        let mut buf = Vec::with_capacity(super::wire::QID_SIZE);
+        super::wire::put_qid(&mut buf, self);
         let mut qid = [0; 13];
-        qid[0] = self.qid_type.bits();
-        NetworkEndian::write_u32(&mut qid[1..5], self.version);
-        NetworkEndian::write_u64(&mut qid[5..13], self.path);
+        qid.copy_from_slice(&buf);
         qid
     }
 
+    pub fn from_bytes(qid: &QidRaw) -> Self {
+        super::wire::Decoder::new(qid).get_qid().unwrap()
+    }
+
     pub fn qid_type(&self) -> QidType {
+        QidType::from_bits_truncate(self.qid_type)
+    }
+
+    pub fn raw_type(&self) -> u8 {
         self.qid_type
     }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn path(&self) -> u64 {
+        self.path
+    }
 }
 
 bitflags! {
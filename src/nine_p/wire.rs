@@ -0,0 +1,261 @@
+//! Little-endian encoding of the 9P primitive types. Everything that puts
+//! a 9P structure on the wire or parses one back goes through here.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use core::str;
+use byteorder::{LittleEndian, ByteOrder};
+use super::qidpool::Qid;
+use super::dir::Dir;
+use super::{DevError, Result};
+
+pub const QID_SIZE: usize = 13;
+
+/// type[2] dev[4] qid[13] mode[4] atime[4] mtime[4] length[8] and the four
+/// string length prefixes, not counting the leading size[2].
+pub const STAT_FIXED_SIZE: usize = 2 + 4 + QID_SIZE + 4 + 4 + 4 + 8 + 4 * 2;
+
+pub fn put_u8(buf: &mut Vec<u8>, n: u8) {
+    buf.push(n);
+}
+
+pub fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    let mut b = [0; 2];
+    LittleEndian::write_u16(&mut b, n);
+    buf.extend_from_slice(&b);
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    let mut b = [0; 4];
+    LittleEndian::write_u32(&mut b, n);
+    buf.extend_from_slice(&b);
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    let mut b = [0; 8];
+    LittleEndian::write_u64(&mut b, n);
+    buf.extend_from_slice(&b);
+}
+
+pub fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_u16(buf, s.len() as u16);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+pub fn put_qid(buf: &mut Vec<u8>, qid: &Qid) {
+    put_u8(buf, qid.raw_type());
+    put_u32(buf, qid.version());
+    put_u64(buf, qid.path());
+}
+
+pub fn stat_size(dir: &Dir) -> usize {
+    2 + STAT_FIXED_SIZE + dir.name().len() + dir.uid().len() + dir.gid().len() + dir.muid().len()
+}
+
+pub fn put_stat(buf: &mut Vec<u8>, dir: &Dir) {
+    put_u16(buf, (stat_size(dir) - 2) as u16);
+    put_u16(buf, dir.dir_type());
+    put_u32(buf, dir.dev());
+    put_qid(buf, &dir.qid());
+    put_u32(buf, dir.mode());
+    put_u32(buf, dir.atime());
+    put_u32(buf, dir.mtime());
+    put_u64(buf, dir.length());
+    put_string(buf, dir.name());
+    put_string(buf, dir.uid());
+    put_string(buf, dir.gid());
+    put_string(buf, dir.muid());
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn get_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.remaining() < n {
+            return Err(DevError::BadMessage);
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16> {
+        Ok(LittleEndian::read_u16(self.get_bytes(2)?))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.get_bytes(4)?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64> {
+        Ok(LittleEndian::read_u64(self.get_bytes(8)?))
+    }
+
+    pub fn get_string(&mut self) -> Result<String> {
+        let len = self.get_u16()? as usize;
+        match str::from_utf8(self.get_bytes(len)?) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(DevError::BadMessage)
+        }
+    }
+
+    pub fn get_qid(&mut self) -> Result<Qid> {
+        let qid_type = self.get_u8()?;
+        let version = self.get_u32()?;
+        let path = self.get_u64()?;
+        Ok(Qid::from_raw_type(qid_type, version, path))
+    }
+
+    pub fn get_stat(&mut self) -> Result<Dir> {
+        let len = self.get_u16()? as usize;
+        if len < STAT_FIXED_SIZE {
+            return Err(DevError::BadMessage);
+        }
+
+        let mut d = Decoder::new(self.get_bytes(len)?);
+        let dir_type = d.get_u16()?;
+        let dev = d.get_u32()?;
+        let qid = d.get_qid()?;
+        let mode = d.get_u32()?;
+        let atime = d.get_u32()?;
+        let mtime = d.get_u32()?;
+        let length = d.get_u64()?;
+        let name = d.get_string()?;
+        let uid = d.get_string()?;
+        let gid = d.get_string()?;
+        let muid = d.get_string()?;
+
+        if !d.is_empty() {
+            return Err(DevError::BadMessage);
+        }
+
+        Ok(Dir::new(dir_type, dev, &qid, mode, atime, mtime, length, &name, &uid, &gid, &muid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use super::*;
+    use super::super::qidpool::QidType;
+    use super::super::dir::DMDIR;
+
+    /// The stat entry of a directory `/usr` as a Plan 9 file server sends
+    /// it.
+    const USR_STAT: [u8; 67] = [
+        0x41, 0x00,                                             // size
+        0x4d, 0x00,                                             // type 'M'
+        0x01, 0x00, 0x00, 0x00,                                 // dev
+        0x80, 0x02, 0x00, 0x00, 0x00,                           // qid type, version
+        0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,         // qid path
+        0xed, 0x01, 0x00, 0x80,                                 // mode DMDIR|0755
+        0x00, 0x10, 0x5e, 0x5f,                                 // atime
+        0x01, 0x10, 0x5e, 0x5f,                                 // mtime
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,         // length
+        0x03, 0x00, b'u', b's', b'r',
+        0x06, 0x00, b'g', b'l', b'e', b'n', b'd', b'a',
+        0x03, 0x00, b's', b'y', b's',
+        0x06, 0x00, b'g', b'l', b'e', b'n', b'd', b'a',
+    ];
+
+    fn usr() -> Dir {
+        let qid = Qid::new(QidType::DIRECTORY, 2, 0x10);
+        Dir::new(0x4d, 1, &qid, DMDIR | 0o755, 0x5f5e_1000, 0x5f5e_1001, 0, "usr", "glenda", "sys", "glenda")
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        let mut buf = Vec::new();
+        put_u8(&mut buf, 0x01);
+        put_u16(&mut buf, 0x0302);
+        put_u32(&mut buf, 0x0706_0504);
+        put_u64(&mut buf, 0x0f0e_0d0c_0b0a_0908);
+        put_string(&mut buf, "ab");
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 2, 0, b'a', b'b']);
+
+        let mut d = Decoder::new(&buf);
+        assert_eq!(d.get_u8().unwrap(), 0x01);
+        assert_eq!(d.get_u16().unwrap(), 0x0302);
+        assert_eq!(d.get_u32().unwrap(), 0x0706_0504);
+        assert_eq!(d.get_u64().unwrap(), 0x0f0e_0d0c_0b0a_0908);
+        assert_eq!(d.get_string().unwrap(), "ab");
+        assert!(d.is_empty());
+        assert!(d.get_u8().is_err());
+    }
+
+    #[test]
+    fn qid_bytes() {
+        let qid = Qid::new(QidType::DIRECTORY | QidType::APPEND_ONLY, 0x0403_0201, 0x0807_0605_0403_0201);
+        let want = [0xc0, 0x01, 0x02, 0x03, 0x04, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+        assert_eq!(qid.as_bytes(), want);
+        assert_eq!(Qid::from_bytes(&want), qid);
+    }
+
+    #[test]
+    fn stat_bytes() {
+        assert_eq!(usr().as_bytes(), USR_STAT.to_vec());
+        assert_eq!(stat_size(&usr()), USR_STAT.len());
+        assert_eq!(Dir::from_bytes(&USR_STAT).unwrap(), usr());
+    }
+
+    #[test]
+    fn stat_list() {
+        let mut buf = USR_STAT.to_vec();
+        buf.extend_from_slice(&USR_STAT);
+        assert_eq!(Dir::list_from_bytes(&buf).unwrap(), vec![usr(), usr()]);
+        assert!(Dir::list_from_bytes(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bad_stats() {
+        assert!(Dir::from_bytes(&USR_STAT[..USR_STAT.len() - 1]).is_err());
+
+        let mut long = USR_STAT.to_vec();
+        long.push(0);
+        assert!(Dir::from_bytes(&long).is_err());
+        long[0] += 1;
+        assert!(Dir::from_bytes(&long).is_err());
+
+        let mut short = USR_STAT.to_vec();
+        short[0] = (STAT_FIXED_SIZE - 1) as u8;
+        assert!(Dir::from_bytes(&short).is_err());
+    }
+
+    #[test]
+    fn null_wstat() {
+        let null = Dir::null();
+        let bytes = null.as_bytes();
+        assert_eq!(bytes.len(), 2 + STAT_FIXED_SIZE);
+        assert_eq!(&bytes[..2], &[STAT_FIXED_SIZE as u8, 0]);
+        assert!(bytes[2..2 + STAT_FIXED_SIZE - 8].iter().all(|b| *b == 0xff));
+        assert!(bytes[2 + STAT_FIXED_SIZE - 8..].iter().all(|b| *b == 0));
+
+        let back = Dir::from_bytes(&bytes).unwrap();
+        assert!(back.is_null());
+        assert_eq!(back.qid().raw_type(), 0xff);
+        assert_eq!(back, null);
+    }
+}
@@ -0,0 +1,491 @@
+use lazy_static::lazy_static;
+use alloc::collections;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use alloc::format;
+use spin::{Mutex, RwLock};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::dev;
+use crate::namespace;
+use crate::nine_p;
+use crate::sched;
+use crate::clock;
+
+pub type Pid = u64;
+
+/// The user that owns the kernel and its devices.
+pub const HOSTOWNER: &str = "eve";
+
+/// The most file descriptors a process can have open at once.
+pub const MAX_FDS: usize = 100;
+
+/// The most notes a process can have pending. Later ones are dropped.
+pub const NNOTE: usize = 5;
+
+/// The size of a note, including its terminating NUL.
+pub const ERRMAX: usize = 128;
+
+bitflags! {
+    pub struct RFork: u32 {
+        /// Give the process a copy of the parent's namespace.
+        const RFNAMEG = 1 << 0;
+        /// Give the process a copy of the parent's environment.
+        const RFENVG = 1 << 1;
+        /// Give the process a copy of the parent's file descriptor table.
+        const RFFDG = 1 << 2;
+        const RFNOTEG = 1 << 3;
+        /// Create a new process rather than changing the current one.
+        const RFPROC = 1 << 4;
+        const RFMEM = 1 << 5;
+        const RFNOWAIT = 1 << 6;
+        /// Start the process with an empty namespace.
+        const RFCNAMEG = 1 << 10;
+        /// Start the process with an empty environment.
+        const RFCENVG = 1 << 11;
+        /// Start the process with no open file descriptors.
+        const RFCFDG = 1 << 12;
+        const RFREND = 1 << 13;
+        /// Forbid the process from mounting or attaching to devices.
+        const RFNOMNT = 1 << 14;
+    }
+}
+
+/// An open file: the channel and the offset that reads and writes through
+/// the descriptor continue from. Descriptors made by `dup` or shared by
+/// rfork refer to the same `Fd`, so they share the offset.
+#[derive(Debug)]
+pub struct Fd {
+    chan: dev::Chan,
+    offset: u64,
+}
+
+impl Fd {
+    pub fn new(chan: dev::Chan) -> Self {
+        Self {
+            chan,
+            offset: 0
+        }
+    }
+
+    pub fn chan(&self) -> &dev::Chan {
+        &self.chan
+    }
+
+    pub fn chan_mut(&mut self) -> &mut dev::Chan {
+        &mut self.chan
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+}
+
+pub type FdRef = Arc<Mutex<Fd>>;
+
+/// A file descriptor table, possibly shared between processes.
+#[derive(Debug, Clone)]
+pub struct FdTable {
+    fds: Vec<Option<FdRef>>
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self {
+            fds: Vec::new()
+        }
+    }
+
+    /// Installs `fd` in the lowest free slot and returns its number.
+    pub fn insert(&mut self, fd: FdRef) -> nine_p::Result<usize> {
+        match self.fds.iter().position(|f| f.is_none()) {
+            Some(i) => {
+                self.fds[i] = Some(fd);
+                Ok(i)
+            }
+            None => {
+                if self.fds.len() >= MAX_FDS {
+                    return Err(nine_p::DevError::Str("no free file descriptors".to_owned()));
+                }
+                self.fds.push(Some(fd));
+                Ok(self.fds.len() - 1)
+            }
+        }
+    }
+
+    /// Installs `fd` as number `n`, closing whatever was there.
+    pub fn insert_at(&mut self, n: usize, fd: FdRef) -> nine_p::Result<()> {
+        if n >= MAX_FDS {
+            return Err(bad_fd());
+        }
+        while self.fds.len() <= n {
+            self.fds.push(None);
+        }
+        self.fds[n] = Some(fd);
+        Ok(())
+    }
+
+    pub fn get(&self, n: usize) -> nine_p::Result<FdRef> {
+        match self.fds.get(n) {
+            Some(Some(fd)) => Ok(fd.clone()),
+            _ => Err(bad_fd())
+        }
+    }
+
+    pub fn remove(&mut self, n: usize) -> nine_p::Result<FdRef> {
+        match self.fds.get_mut(n) {
+            Some(fd) => fd.take().ok_or_else(bad_fd),
+            None => Err(bad_fd())
+        }
+    }
+}
+
+fn bad_fd() -> nine_p::DevError {
+    nine_p::DevError::Str("fd out of range or not open".to_owned())
+}
+
+pub type Environment = collections::BTreeMap<String, Vec<u8>>;
+
+/// The exit messages of a process's children, waiting to be collected by
+/// await. The counts are atomics so a sleeping parent can test them with
+/// interrupts off without taking a lock.
+#[derive(Debug)]
+pub struct WaitQueue {
+    msgs: Mutex<VecDeque<String>>,
+    pending: AtomicUsize,
+    children: AtomicUsize,
+    rendez: sched::Rendez,
+}
+
+impl WaitQueue {
+    fn new() -> Self {
+        Self {
+            msgs: Mutex::new(VecDeque::new()),
+            pending: AtomicUsize::new(0),
+            children: AtomicUsize::new(0),
+            rendez: sched::Rendez::new(),
+        }
+    }
+
+    /// Waits for a child to exit and returns its wait message, formatted
+    /// as Plan 9's await does.
+    pub fn await_child(&self) -> nine_p::Result<String> {
+        if self.pending.load(Ordering::SeqCst) == 0 && self.children.load(Ordering::SeqCst) == 0 {
+            return Err(nine_p::DevError::Str("no living children".to_owned()));
+        }
+        self.rendez.sleep(|| self.pending.load(Ordering::SeqCst) > 0);
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        Ok(self.msgs.lock().pop_front().expect("wait message went missing"))
+    }
+
+    fn post(&self, msg: String) {
+        self.msgs.lock().push_back(msg);
+        self.children.fetch_sub(1, Ordering::SeqCst);
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.rendez.wakeup();
+    }
+}
+
+#[derive(Debug)]
+pub struct Proc {
+    pid: Pid,
+    parent: Pid,
+    user: String,
+    namespace: Arc<RwLock<namespace::Namespace>>,
+    fds: Arc<RwLock<FdTable>>,
+    env: Arc<RwLock<Environment>>,
+    dot: String,
+    errstr: String,
+    no_mount: bool,
+    wait: Arc<WaitQueue>,
+    start: u64,
+    notes: VecDeque<String>,
+    /// The user's note handler, or 0 for none.
+    notify: u64,
+    /// The note being handled and where the registers it interrupted were
+    /// saved, while the handler runs.
+    notified: Option<(String, u64)>,
+}
+
+impl Proc {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Pid {
+        self.parent
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn namespace(&self) -> Arc<RwLock<namespace::Namespace>> {
+        self.namespace.clone()
+    }
+
+    pub fn fds(&self) -> Arc<RwLock<FdTable>> {
+        self.fds.clone()
+    }
+
+    pub fn env(&self) -> Arc<RwLock<Environment>> {
+        self.env.clone()
+    }
+
+    /// The current directory.
+    pub fn dot(&self) -> &str {
+        &self.dot
+    }
+
+    /// Changes the current directory, which must exist in the namespace.
+    pub fn chdir(&mut self, path: &str) -> nine_p::Result<()> {
+        let path = self.abs_path(path);
+        let chan = self.namespace.read().walk(&path)?;
+        if !chan.is_dir() {
+            return Err(nine_p::DevError::NotADir);
+        }
+        self.dot = path;
+        Ok(())
+    }
+
+    /// Makes `path` absolute by resolving it against the current directory.
+    pub fn abs_path(&self, path: &str) -> String {
+        if path.starts_with('/') || path.starts_with('#') {
+            namespace::clean_name(path)
+        } else {
+            namespace::clean_name(&format!("{}/{}", self.dot, path))
+        }
+    }
+
+    pub fn errstr(&self) -> &str {
+        &self.errstr
+    }
+
+    pub fn set_errstr(&mut self, err: &str) {
+        self.errstr = err.to_owned();
+    }
+
+    /// Swaps the error string for `err`, as the errstr system call does.
+    pub fn swap_errstr(&mut self, err: String) -> String {
+        core::mem::replace(&mut self.errstr, err)
+    }
+
+    pub fn wait_queue(&self) -> Arc<WaitQueue> {
+        self.wait.clone()
+    }
+
+    pub fn has_notes(&self) -> bool {
+        !self.notes.is_empty()
+    }
+
+    /// Queues a note, truncated to fit ERRMAX. A system note, such as one
+    /// for a trap, throws away the pending notes if nothing would handle
+    /// them, so that it's the one that ends the process.
+    pub fn post_note(&mut self, note: &str, system: bool) {
+        if system && (self.notify == 0 || self.notified.is_some()) {
+            self.notes.clear();
+        }
+        if self.notes.len() < NNOTE {
+            let mut len = core::cmp::min(note.len(), ERRMAX - 1);
+            while !note.is_char_boundary(len) {
+                len -= 1;
+            }
+            self.notes.push_back(note[..len].to_owned());
+        }
+    }
+
+    /// Sets the address of the note handler, 0 for none.
+    pub fn set_notify(&mut self, handler: u64) {
+        self.notify = handler;
+    }
+
+    /// Takes the oldest pending note to deliver, with the handler to run
+    /// for it, or 0 if it should end the process instead. Nothing more is
+    /// delivered while a handler runs, except system notes, which end the
+    /// process.
+    pub fn take_note(&mut self) -> Option<(String, u64)> {
+        let system = self.notes.front()?.starts_with("sys:");
+        if self.notified.is_some() {
+            if !system {
+                return None;
+            }
+            return Some((self.notes.pop_front().unwrap(), 0));
+        }
+        Some((self.notes.pop_front().unwrap(), self.notify))
+    }
+
+    /// Records that the handler is running for `note`, with the registers
+    /// it interrupted saved at `ureg` in user memory.
+    pub fn set_notified(&mut self, note: String, ureg: u64) {
+        self.notified = Some((note, ureg));
+    }
+
+    /// Ends the running handler, returning its note and where the
+    /// registers were saved.
+    pub fn noted(&mut self) -> Option<(String, u64)> {
+        self.notified.take()
+    }
+
+    /// Forgets the note handler, as exec does.
+    pub fn reset_notify(&mut self) {
+        self.notify = 0;
+        self.notified = None;
+    }
+
+    /// Whether the process has given up the right to mount and attach.
+    pub fn no_mount(&self) -> bool {
+        self.no_mount
+    }
+
+    /// Replaces or detaches the resource groups named by `flags`. With
+    /// neither the copy nor the clean flag for a group it stays shared.
+    fn apply_rfork(&mut self, flags: RFork) -> nine_p::Result<()> {
+        if flags.contains(RFork::RFNAMEG | RFork::RFCNAMEG) ||
+            flags.contains(RFork::RFENVG | RFork::RFCENVG) ||
+            flags.contains(RFork::RFFDG | RFork::RFCFDG) {
+            return Err(nine_p::DevError::Str("bad arg in system call".to_owned()));
+        }
+
+        if flags.contains(RFork::RFNAMEG) {
+            let ns = self.namespace.read().clone();
+            self.namespace = Arc::new(RwLock::new(ns));
+        } else if flags.contains(RFork::RFCNAMEG) {
+            self.namespace = Arc::new(RwLock::new(namespace::Namespace::new()));
+        }
+
+        if flags.contains(RFork::RFENVG) {
+            let env = self.env.read().clone();
+            self.env = Arc::new(RwLock::new(env));
+        } else if flags.contains(RFork::RFCENVG) {
+            self.env = Arc::new(RwLock::new(Environment::new()));
+        }
+
+        if flags.contains(RFork::RFFDG) {
+            let fds = self.fds.read().clone();
+            self.fds = Arc::new(RwLock::new(fds));
+        } else if flags.contains(RFork::RFCFDG) {
+            self.fds = Arc::new(RwLock::new(FdTable::new()));
+        }
+
+        if flags.contains(RFork::RFNOMNT) {
+            self.no_mount = true;
+        }
+        Ok(())
+    }
+
+    /// Makes a child of this process sharing every resource group, then
+    /// applies `flags` to it.
+    fn fork(&self, pid: Pid, flags: RFork) -> nine_p::Result<Proc> {
+        let mut child = Proc {
+            pid,
+            parent: if flags.contains(RFork::RFNOWAIT) { 0 } else { self.pid },
+            user: self.user.clone(),
+            namespace: self.namespace.clone(),
+            fds: self.fds.clone(),
+            env: self.env.clone(),
+            dot: self.dot.clone(),
+            errstr: String::new(),
+            no_mount: self.no_mount,
+            wait: Arc::new(WaitQueue::new()),
+            start: clock::ticks(),
+            notes: VecDeque::new(),
+            notify: self.notify,
+            notified: None,
+        };
+        child.apply_rfork(flags)?;
+        Ok(child)
+    }
+}
+
+struct ProcTable {
+    procs: collections::BTreeMap<Pid, Arc<RwLock<Proc>>>,
+    next_pid: Pid,
+}
+
+lazy_static! {
+    static ref PROCS: RwLock<ProcTable> = RwLock::new(ProcTable {
+        procs: collections::BTreeMap::new(),
+        next_pid: 1,
+    });
+}
+
+/// Creates the first process, owned by the host owner, with the given
+/// namespace and gives it the running thread.
+pub fn init(ns: namespace::Namespace) -> Pid {
+    assert_has_not_been_called!("proc::init must be called only once");
+
+    let mut table = PROCS.write();
+    let pid = table.next_pid;
+    table.next_pid += 1;
+    table.procs.insert(pid, Arc::new(RwLock::new(Proc {
+        pid,
+        parent: 0,
+        user: HOSTOWNER.to_owned(),
+        namespace: Arc::new(RwLock::new(ns)),
+        fds: Arc::new(RwLock::new(FdTable::new())),
+        env: Arc::new(RwLock::new(Environment::new())),
+        dot: "/".to_owned(),
+        errstr: String::new(),
+        no_mount: false,
+        wait: Arc::new(WaitQueue::new()),
+        start: clock::ticks(),
+        notes: VecDeque::new(),
+        notify: 0,
+        notified: None,
+    })));
+    sched::set_proc(Some(pid));
+    pid
+}
+
+pub fn get(pid: Pid) -> Option<Arc<RwLock<Proc>>> {
+    PROCS.read().procs.get(&pid).cloned()
+}
+
+/// The process the running thread belongs to.
+pub fn current() -> Arc<RwLock<Proc>> {
+    let pid = sched::current_proc().expect("no current process");
+    get(pid).expect("current process not in the process table")
+}
+
+/// Applies `flags` to the current process. With RFPROC a child is created
+/// instead and its pid returned; otherwise the current process's own
+/// resource groups are changed and 0 is returned.
+pub fn rfork(flags: RFork) -> nine_p::Result<Pid> {
+    let cur = current();
+    if !flags.contains(RFork::RFPROC) {
+        cur.write().apply_rfork(flags)?;
+        return Ok(0);
+    }
+
+    let mut table = PROCS.write();
+    let pid = table.next_pid;
+    let child = cur.read().fork(pid, flags)?;
+    table.next_pid += 1;
+    table.procs.insert(pid, Arc::new(RwLock::new(child)));
+    if !flags.contains(RFork::RFNOWAIT) {
+        cur.read().wait.children.fetch_add(1, Ordering::SeqCst);
+    }
+    Ok(pid)
+}
+
+/// Ends the running process with the exit message `msg`, which its parent
+/// can collect with await, and its thread with it.
+pub fn exits(msg: &str) -> ! {
+    let pid = sched::current_proc().expect("no current process");
+    let removed = PROCS.write().procs.remove(&pid);
+    if let Some(p) = removed {
+        let p = p.read();
+        if let Some(parent) = get(p.parent) {
+            let elapsed = clock::ticks_to_ms(clock::ticks() - p.start);
+            parent.read().wait.post(format!("{} 0 0 {} '{}'", pid, elapsed, msg));
+        }
+    }
+    sched::set_proc(None);
+    sched::exit();
+}
@@ -0,0 +1,612 @@
+use alloc::collections;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cmp::min;
+use spin::RwLock;
+use crate::nine_p;
+use crate::nine_p::dir::{Dir, DMDIR, DMAPPEND, DMEXCL, DMTMP};
+use crate::nine_p::qidpool::{Qid, QidType};
+
+type NodeId = u64;
+
+/// The largest a file may grow, since it is all on the kernel heap.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+fn file_too_large() -> nine_p::DevError {
+    nine_p::DevError::Str("file too large".to_owned())
+}
+
+fn mode_to_qid_type(mode: u32) -> QidType {
+    let mut qid_type = QidType::FILE;
+    if mode & DMDIR != 0 {
+        qid_type |= QidType::DIRECTORY;
+    }
+    if mode & DMAPPEND != 0 {
+        qid_type |= QidType::APPEND_ONLY;
+    }
+    if mode & DMEXCL != 0 {
+        qid_type |= QidType::EXCLUSIVE_USE;
+    }
+    if mode & DMTMP != 0 {
+        qid_type |= QidType::TEMPORARY_FILE;
+    }
+    qid_type
+}
+
+fn access_to_perm(access: nine_p::FileAccessMode) -> u32 {
+    match access {
+        nine_p::FileAccessMode::Read => 4,
+        nine_p::FileAccessMode::Write => 2,
+        nine_p::FileAccessMode::ReadWrite => 6,
+        nine_p::FileAccessMode::Execute => 1,
+    }
+}
+
+#[derive(Debug)]
+enum Contents {
+    File(Arc<RwLock<Vec<u8>>>),
+    Dir(collections::BTreeMap<String, NodeId>),
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: NodeId,
+    mode: u32,
+    version: u32,
+    atime: u32,
+    mtime: u32,
+    uid: String,
+    gid: String,
+    muid: String,
+    contents: Contents,
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        self.mode & DMDIR != 0
+    }
+
+    fn length(&self) -> u64 {
+        match &self.contents {
+            Contents::File(data) => data.read().len() as u64,
+            Contents::Dir(_) => 0
+        }
+    }
+
+    fn has_perm(&self, uname: &str, want: u32) -> bool {
+        let mut perm = self.mode & 7;
+        if self.gid == uname {
+            perm |= (self.mode >> 3) & 7;
+        }
+        if self.uid == uname {
+            perm |= (self.mode >> 6) & 7;
+        }
+        perm & want == want
+    }
+}
+
+#[derive(Debug)]
+struct RamFile {
+    data: Arc<RwLock<Vec<u8>>>,
+    append: bool,
+}
+
+impl nine_p::FileRWC for RamFile {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> nine_p::Result<usize> {
+        let data = self.data.read();
+        let pos = pos as usize;
+        if pos >= data.len() {
+            return Err(nine_p::DevError::EOF);
+        }
+
+        let read_len = min(buf.len(), data.len() - pos);
+        buf[..read_len].copy_from_slice(&data[pos..pos + read_len]);
+        Ok(read_len)
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> nine_p::Result<usize> {
+        let mut data = self.data.write();
+        let pos = if self.append { data.len() } else { pos as usize };
+        let end = match pos.checked_add(buf.len()) {
+            Some(end) if end as u64 <= MAX_FILE_SIZE => end,
+            _ => return Err(file_too_large())
+        };
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[pos..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+#[derive(Debug)]
+struct RamFid {
+    node: NodeId,
+    uname: String,
+    file: nine_p::File<'static>,
+    remove_on_close: bool,
+}
+
+impl RamFid {
+    fn is_open(&self) -> bool {
+        self.file.rwc().is_some()
+    }
+}
+
+/// Writable file trees held entirely on the kernel heap, in the spirit of
+/// Plan 9's ramfs. Every attach name gets a tree of its own, so `#R` and
+/// `#Rscratch` are separate file systems.
+#[derive(Debug)]
+pub struct RamFSServer {
+    name: char,
+    description: &'static str,
+    roots: collections::BTreeMap<String, NodeId>,
+    nodes: collections::BTreeMap<NodeId, Node>,
+    next_node: NodeId,
+    fids: collections::BTreeMap<nine_p::Fid, RamFid>,
+}
+
+impl RamFSServer {
+    pub fn new(name: char, description: &'static str) -> Self {
+        Self {
+            name,
+            description,
+            roots: collections::BTreeMap::new(),
+            nodes: collections::BTreeMap::new(),
+            next_node: 0,
+            fids: collections::BTreeMap::new(),
+        }
+    }
+
+    /// Returns the root of the tree for `aname`, creating it on first use
+    /// and giving it to the attaching user.
+    fn root(&mut self, aname: &str, uname: &str) -> NodeId {
+        if let Some(id) = self.roots.get(aname) {
+            return *id;
+        }
+
+        let id = self.next_node;
+        self.next_node += 1;
+        self.nodes.insert(id, Node {
+            name: "/".to_owned(),
+            parent: id,
+            mode: DMDIR | 0o777,
+            version: 0,
+            atime: 0,
+            mtime: 0,
+            uid: uname.to_owned(),
+            gid: uname.to_owned(),
+            muid: uname.to_owned(),
+            contents: Contents::Dir(collections::BTreeMap::new())
+        });
+        self.roots.insert(aname.to_owned(), id);
+        id
+    }
+
+    fn is_root(&self, id: NodeId) -> bool {
+        match self.nodes.get(&id) {
+            Some(n) => n.parent == id,
+            None => false
+        }
+    }
+
+    fn check_fid(&self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        if !self.fids.contains_key(&fid) {
+            return Err(nine_p::DevError::NoFid);
+        }
+        Ok(())
+    }
+
+    fn check_fid_in_use(&self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        if self.fids.contains_key(&fid) {
+            return Err(nine_p::DevError::FidInUse);
+        }
+        Ok(())
+    }
+
+    fn node(&self, id: NodeId) -> nine_p::Result<&Node> {
+        match self.nodes.get(&id) {
+            Some(n) => Ok(n),
+            None => Err(nine_p::DevError::NoSuchFile)
+        }
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> nine_p::Result<&mut Node> {
+        match self.nodes.get_mut(&id) {
+            Some(n) => Ok(n),
+            None => Err(nine_p::DevError::NoSuchFile)
+        }
+    }
+
+    fn qid(&self, id: NodeId) -> nine_p::Result<Qid> {
+        let node = self.node(id)?;
+        Ok(Qid::new(mode_to_qid_type(node.mode), node.version, id))
+    }
+
+    fn node_to_dir(&self, id: NodeId) -> nine_p::Result<Dir> {
+        let node = self.node(id)?;
+        Ok(Dir::new(0, 0, &self.qid(id)?, node.mode, node.atime, node.mtime, node.length(),
+                    &node.name, &node.uid, &node.gid, &node.muid))
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> nine_p::Result<NodeId> {
+        let node = self.node(dir)?;
+        if name == ".." {
+            return Ok(node.parent);
+        }
+        match &node.contents {
+            Contents::Dir(children) => match children.get(name) {
+                Some(id) => Ok(*id),
+                None => Err(nine_p::DevError::NoSuchFile)
+            },
+            Contents::File(_) => Err(nine_p::DevError::NotADir)
+        }
+    }
+
+    fn remove_node(&mut self, id: NodeId, uname: &str) -> nine_p::Result<()> {
+        if self.is_root(id) {
+            return Err(nine_p::DevError::PermissionDenied);
+        }
+
+        let (parent, name) = {
+            let node = self.node(id)?;
+            if let Contents::Dir(children) = &node.contents {
+                if !children.is_empty() {
+                    return Err(nine_p::DevError::Str("directory not empty".to_owned()));
+                }
+            }
+            (node.parent, node.name.clone())
+        };
+
+        if !self.node(parent)?.has_perm(uname, 2) {
+            return Err(nine_p::DevError::PermissionDenied);
+        }
+
+        if let Contents::Dir(children) = &mut self.node_mut(parent)?.contents {
+            children.remove(&name);
+        }
+        self.nodes.remove(&id);
+        Ok(())
+    }
+}
+
+impl nine_p::NinePServer for RamFSServer {
+    fn name(&self) -> char {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn auth(&mut self, _afid: nine_p::Fid, _uname: &str, _aname: &str) -> nine_p::Result<Qid> {
+        Err(nine_p::DevError::AuthNotNeeded)
+    }
+
+    fn attach(&mut self, fid: nine_p::Fid, afid: nine_p::Fid, uname: &str, aname: &str) -> nine_p::Result<Qid> {
+        if afid != nine_p::NO_FID {
+            return Err(nine_p::DevError::AuthNotNeeded);
+        }
+
+        self.check_fid_in_use(fid)?;
+
+        let root = self.root(aname, uname);
+        self.fids.insert(fid, RamFid {
+            node: root,
+            uname: uname.to_owned(),
+            file: nine_p::File::new("/", false, None),
+            remove_on_close: false,
+        });
+        self.qid(root)
+    }
+
+    fn clunk(&mut self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        match self.fids.remove(&fid) {
+            Some(f) => {
+                if f.remove_on_close {
+                    let _ = self.remove_node(f.node, &f.uname);
+                }
+                Ok(())
+            }
+            None => Err(nine_p::DevError::NoFid)
+        }
+    }
+
+    fn open(&mut self, fid: nine_p::Fid, mode: &nine_p::FileMode) -> nine_p::Result<(Qid, u32)> {
+        self.check_fid(fid)?;
+
+        let (id, uname) = {
+            let f = self.fids.get(&fid).unwrap();
+            if f.is_open() {
+                return Err(nine_p::DevError::FileOpen);
+            }
+            (f.node, f.uname.clone())
+        };
+
+        let mut want = access_to_perm(mode.access());
+        if mode.truncate() {
+            want |= 2;
+        }
+
+        let rwc: Box<dyn nine_p::FileRWC> = {
+            let node = self.node(id)?;
+            if !node.has_perm(&uname, want) {
+                return Err(nine_p::DevError::PermissionDenied);
+            }
+            if mode.remove_on_close() && !self.node(node.parent)?.has_perm(&uname, 2) {
+                return Err(nine_p::DevError::PermissionDenied);
+            }
+
+            match &node.contents {
+                Contents::Dir(children) => {
+                    if mode.access() != nine_p::FileAccessMode::Read || mode.truncate() {
+                        return Err(nine_p::DevError::PermissionDenied);
+                    }
+                    let mut entries = Vec::new();
+                    for child in children.values() {
+                        entries.push(self.node_to_dir(*child)?);
+                    }
+                    let reader = nine_p::dir::ListReader::new(entries);
+                    Box::new(nine_p::RWCWrapper::new(Box::new(reader)))
+                }
+                Contents::File(data) => {
+                    if mode.truncate() {
+                        data.write().clear();
+                    }
+                    Box::new(RamFile {
+                        data: data.clone(),
+                        append: node.mode & DMAPPEND != 0
+                    })
+                }
+            }
+        };
+
+        if mode.truncate() {
+            let node = self.node_mut(id)?;
+            node.version += 1;
+            node.muid = uname;
+        }
+
+        let f = self.fids.get_mut(&fid).unwrap();
+        f.file.set_rwc(rwc);
+        f.file.set_access(mode.access());
+        f.remove_on_close = mode.remove_on_close();
+
+        Ok((self.qid(id)?, 0))
+    }
+
+    fn create(&mut self, fid: nine_p::Fid, name: &str, perm: u32, mode: &nine_p::FileMode) -> nine_p::Result<(Qid, u32)> {
+        self.check_fid(fid)?;
+
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(nine_p::DevError::Str("bad file name".to_owned()));
+        }
+
+        let (dir_id, uname) = {
+            let f = self.fids.get(&fid).unwrap();
+            if f.is_open() {
+                return Err(nine_p::DevError::FileOpen);
+            }
+            (f.node, f.uname.clone())
+        };
+
+        let (node_mode, gid) = {
+            let dir = self.node(dir_id)?;
+            match &dir.contents {
+                Contents::Dir(children) => {
+                    if children.contains_key(name) {
+                        return Err(nine_p::DevError::Str("file already exists".to_owned()));
+                    }
+                }
+                Contents::File(_) => return Err(nine_p::DevError::NotADir)
+            }
+            if !dir.has_perm(&uname, 2) {
+                return Err(nine_p::DevError::PermissionDenied);
+            }
+
+            let node_mode = if perm & DMDIR != 0 {
+                perm & (!0o777 | (dir.mode & 0o777))
+            } else {
+                perm & (!0o666 | (dir.mode & 0o666))
+            };
+            (node_mode, dir.gid.clone())
+        };
+
+        if node_mode & DMDIR != 0 && (mode.access() != nine_p::FileAccessMode::Read || mode.truncate()) {
+            return Err(nine_p::DevError::PermissionDenied);
+        }
+
+        let id = self.next_node;
+        self.next_node += 1;
+        self.nodes.insert(id, Node {
+            name: name.to_owned(),
+            parent: dir_id,
+            mode: node_mode,
+            version: 0,
+            atime: 0,
+            mtime: 0,
+            uid: uname.clone(),
+            gid,
+            muid: uname,
+            contents: if node_mode & DMDIR != 0 {
+                Contents::Dir(collections::BTreeMap::new())
+            } else {
+                Contents::File(Arc::new(RwLock::new(Vec::new())))
+            }
+        });
+        if let Contents::Dir(children) = &mut self.node_mut(dir_id)?.contents {
+            children.insert(name.to_owned(), id);
+        }
+
+        {
+            let f = self.fids.get_mut(&fid).unwrap();
+            f.node = id;
+            f.file = nine_p::File::new(name, false, None);
+        }
+
+        // The creator gets the file opened as asked for even if the new
+        // permissions wouldn't otherwise allow it.
+        let rwc: Box<dyn nine_p::FileRWC> = match &self.node(id)?.contents {
+            Contents::Dir(_) => Box::new(nine_p::RWCWrapper::new(
+                Box::new(nine_p::dir::ListReader::new(Vec::new())))),
+            Contents::File(data) => Box::new(RamFile {
+                data: data.clone(),
+                append: node_mode & DMAPPEND != 0
+            })
+        };
+        let f = self.fids.get_mut(&fid).unwrap();
+        f.file.set_rwc(rwc);
+        f.file.set_access(mode.access());
+        f.remove_on_close = mode.remove_on_close();
+
+        Ok((self.qid(id)?, 0))
+    }
+
+    fn walk(&mut self, fid: nine_p::Fid, new_fid: nine_p::Fid, names: &[&str]) -> nine_p::Result<Vec<Qid>> {
+        self.check_fid(fid)?;
+
+        if fid != new_fid {
+            self.check_fid_in_use(new_fid)?;
+        }
+
+        let (mut id, uname) = {
+            let f = self.fids.get(&fid).unwrap();
+            if f.is_open() {
+                return Err(nine_p::DevError::FileOpen);
+            }
+            (f.node, f.uname.clone())
+        };
+
+        let mut out_qid = Vec::new();
+        for name in names {
+            let node = self.node(id)?;
+            let next = if !node.is_dir() {
+                Err(nine_p::DevError::NotADir)
+            } else if !node.has_perm(&uname, 1) {
+                Err(nine_p::DevError::PermissionDenied)
+            } else {
+                self.lookup(id, name)
+            };
+
+            match next {
+                Ok(next) => {
+                    id = next;
+                    out_qid.push(self.qid(id)?);
+                }
+                Err(e) => {
+                    if out_qid.is_empty() {
+                        return Err(e);
+                    } else {
+                        return Ok(out_qid);
+                    }
+                }
+            }
+        }
+
+        let name = self.node(id)?.name.clone();
+        self.fids.insert(new_fid, RamFid {
+            node: id,
+            uname,
+            file: nine_p::File::new(&name, false, None),
+            remove_on_close: false,
+        });
+
+        Ok(out_qid)
+    }
+
+    fn read(&mut self, fid: nine_p::Fid, offset: u64, count: usize) -> nine_p::Result<Vec<u8>> {
+        self.check_fid(fid)?;
+
+        let f = self.fids.get_mut(&fid).unwrap();
+        nine_p::default_read(&mut f.file, offset, count)
+    }
+
+    fn write(&mut self, fid: nine_p::Fid, offset: u64, data: &[u8]) -> nine_p::Result<usize> {
+        self.check_fid(fid)?;
+
+        let (id, uname, n) = {
+            let f = self.fids.get_mut(&fid).unwrap();
+            let n = nine_p::default_write(&mut f.file, offset, data)?;
+            (f.node, f.uname.clone(), n)
+        };
+
+        let node = self.node_mut(id)?;
+        node.version += 1;
+        node.muid = uname;
+        Ok(n)
+    }
+
+    fn remove(&mut self, fid: nine_p::Fid) -> nine_p::Result<()> {
+        match self.fids.remove(&fid) {
+            Some(f) => self.remove_node(f.node, &f.uname),
+            None => Err(nine_p::DevError::NoFid)
+        }
+    }
+
+    fn stat(&mut self, fid: nine_p::Fid) -> nine_p::Result<Dir> {
+        self.check_fid(fid)?;
+
+        let id = self.fids.get(&fid).unwrap().node;
+        self.node_to_dir(id)
+    }
+
+    fn wstat(&mut self, fid: nine_p::Fid, wstat: &Dir) -> nine_p::Result<()> {
+        self.check_fid(fid)?;
+
+        if wstat.is_null() {
+            return Ok(());
+        }
+
+        let (id, uname) = {
+            let f = self.fids.get(&fid).unwrap();
+            (f.node, f.uname.clone())
+        };
+
+        let mut dir = self.node_to_dir(id)?;
+        dir.apply_wstat(wstat)?;
+
+        let (parent, old_name, owner) = {
+            let node = self.node(id)?;
+            if wstat.length() != !0 && !node.has_perm(&uname, 2) {
+                return Err(nine_p::DevError::PermissionDenied);
+            }
+            if dir.length() > MAX_FILE_SIZE {
+                return Err(file_too_large());
+            }
+            (node.parent, node.name.clone(), node.uid == uname)
+        };
+        if (wstat.mode() != !0 || wstat.mtime() != !0 || !wstat.gid().is_empty()) && !owner {
+            return Err(nine_p::DevError::PermissionDenied);
+        }
+
+        let rename = dir.name() != old_name;
+        if rename {
+            if self.is_root(id) || !self.node(parent)?.has_perm(&uname, 2) {
+                return Err(nine_p::DevError::PermissionDenied);
+            }
+            if self.lookup(parent, dir.name()).is_ok() {
+                return Err(nine_p::DevError::Str("file already exists".to_owned()));
+            }
+            if let Contents::Dir(children) = &mut self.node_mut(parent)?.contents {
+                children.remove(&old_name);
+                children.insert(dir.name().to_owned(), id);
+            }
+        }
+
+        let node = self.node_mut(id)?;
+        node.name = dir.name().to_owned();
+        node.mode = dir.mode();
+        node.mtime = dir.mtime();
+        node.gid = dir.gid().to_owned();
+        if let Contents::File(data) = &node.contents {
+            data.write().resize(dir.length() as usize, 0);
+        }
+        node.version += 1;
+        node.muid = uname;
+        Ok(())
+    }
+}
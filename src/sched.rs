@@ -0,0 +1,399 @@
+//! Kernel threads and a round-robin scheduler. The timer interrupt preempts
+//! the running thread every tick. Only one CPU is supported, so holding
+//! interrupts off is enough to keep the scheduler's state consistent.
+
+use lazy_static::lazy_static;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::{clock, memory, proc, syscall};
+
+pub type Tid = u64;
+
+/// Size of a kernel thread's stack, not counting its guard page.
+pub const KSTACK_PAGES: usize = 4;
+
+extern "C" {
+    fn switch_context(old_rsp: *mut usize, new_rsp: usize);
+    fn thread_trampoline();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Sleeping,
+    Dead,
+}
+
+struct Thread {
+    state: ThreadState,
+    /// The saved stack pointer while the thread isn't running.
+    rsp: usize,
+    /// `None` for the boot thread, which runs on the stack set up in boot.s.
+    stack: Option<memory::Stack>,
+    proc: Option<proc::Pid>,
+    /// The user address space, if the thread has one. Kernel threads run
+    /// on whatever table is loaded, since every table maps the kernel.
+    space: Option<Arc<memory::AddressSpace>>,
+    /// The tick a timed sleep gives up at.
+    wake_at: Option<u64>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+struct Scheduler {
+    threads: BTreeMap<Tid, Box<Thread>>,
+    run_queue: VecDeque<Tid>,
+    current: Tid,
+    idle: Tid,
+    next_tid: Tid,
+    /// Stacks of threads that have exited, kept for reuse.
+    free_stacks: Vec<memory::Stack>,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let mut threads = BTreeMap::new();
+        threads.insert(0, Box::new(Thread {
+            state: ThreadState::Running,
+            rsp: 0,
+            stack: None,
+            proc: None,
+            space: None,
+            wake_at: None,
+            entry: None,
+        }));
+
+        Self {
+            threads,
+            run_queue: VecDeque::new(),
+            current: 0,
+            idle: 0,
+            next_tid: 1,
+            free_stacks: Vec::new(),
+        }
+    }
+
+    fn make_ready(&mut self, tid: Tid) {
+        if let Some(t) = self.threads.get_mut(&tid) {
+            if t.state == ThreadState::Sleeping {
+                t.state = ThreadState::Ready;
+                t.wake_at = None;
+                self.run_queue.push_back(tid);
+            }
+        }
+    }
+
+    /// Frees every exited thread except the one still running.
+    fn reap(&mut self) {
+        let cur = self.current;
+        let dead: Vec<Tid> = self.threads.iter()
+            .filter(|(tid, t)| **tid != cur && t.state == ThreadState::Dead)
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in dead {
+            if let Some(t) = self.threads.remove(&tid) {
+                if let Some(stack) = t.stack {
+                    self.free_stacks.push(stack);
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref SCHED: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+}
+
+fn with_sched<F, R>(f: F) -> R
+    where F: FnOnce(&mut Scheduler) -> R
+{
+    interrupts::without_interrupts(|| f(&mut SCHED.lock()))
+}
+
+/// Makes the running boot code thread 0 and starts the idle thread.
+pub fn init() {
+    assert_has_not_been_called!("sched::init must be called only once");
+
+    let idle = spawn_thread(None, idle_loop, false);
+    with_sched(|s| s.idle = idle);
+}
+
+fn idle_loop() {
+    loop {
+        interrupts::enable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Starts a kernel thread running `f`, on behalf of the process `proc`.
+/// It starts out sharing the spawning thread's address space.
+pub fn spawn<F>(proc: Option<proc::Pid>, f: F) -> Tid
+    where F: FnOnce() + Send + 'static
+{
+    spawn_thread(proc, f, true)
+}
+
+fn spawn_thread<F>(proc: Option<proc::Pid>, f: F, runnable: bool) -> Tid
+    where F: FnOnce() + Send + 'static
+{
+    let stack = match with_sched(|s| s.free_stacks.pop()) {
+        Some(stack) => stack,
+        None => memory::with_controller(|mc| mc.alloc_stack(KSTACK_PAGES))
+            .expect("out of kernel stacks")
+    };
+
+    // switch_context pops six callee-saved registers, then returns into the
+    // trampoline with the stack pointer back at the (aligned) top.
+    let top = stack.top().as_u64() as usize;
+    let rsp = top - 8 * 7;
+    unsafe {
+        let frame = rsp as *mut usize;
+        for i in 0..6 {
+            *frame.add(i) = 0;
+        }
+        *frame.add(6) = thread_trampoline as usize;
+    }
+
+    with_sched(|s| {
+        let tid = s.next_tid;
+        let space = s.threads.get(&s.current).and_then(|t| t.space.clone());
+        s.next_tid += 1;
+        s.threads.insert(tid, Box::new(Thread {
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            proc,
+            space,
+            wake_at: None,
+            entry: Some(Box::new(f)),
+        }));
+        if runnable {
+            s.run_queue.push_back(tid);
+        }
+        tid
+    })
+}
+
+/// Where a new thread starts, with interrupts still off from the switch.
+#[no_mangle]
+pub extern "C" fn thread_entry() -> ! {
+    let entry = with_sched(|s| {
+        let cur = s.current;
+        s.threads.get_mut(&cur).and_then(|t| t.entry.take())
+    });
+    interrupts::enable();
+    if let Some(f) = entry {
+        f();
+    }
+    exit();
+}
+
+/// Switches to the next ready thread, or the idle thread if there is none.
+/// Interrupts must be off.
+fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut s = SCHED.lock();
+        s.reap();
+
+        let cur = s.current;
+        let idle = s.idle;
+        if let Some(t) = s.threads.get_mut(&cur) {
+            if t.state == ThreadState::Running {
+                t.state = ThreadState::Ready;
+                if cur != idle {
+                    s.run_queue.push_back(cur);
+                }
+            }
+        }
+
+        let mut next = idle;
+        while let Some(tid) = s.run_queue.pop_front() {
+            if s.threads.get(&tid).map(|t| t.state) == Some(ThreadState::Ready) {
+                next = tid;
+                break;
+            }
+        }
+
+        s.threads.get_mut(&next).expect("no thread to run").state = ThreadState::Running;
+        if next == cur {
+            return;
+        }
+        s.current = next;
+        if let Some(ref stack) = s.threads[&next].stack {
+            syscall::set_kernel_stack(stack.top());
+        }
+        if let Some(ref space) = s.threads[&next].space {
+            memory::switch_table(space.p4_frame());
+        }
+
+        let old_rsp = &mut s.threads.get_mut(&cur).expect("current thread vanished").rsp as *mut usize;
+        (old_rsp, s.threads[&next].rsp)
+    };
+
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+/// Called from the timer interrupt: wakes threads whose timed sleep is
+/// over and preempts the running thread.
+pub fn tick() {
+    let now = clock::ticks();
+    {
+        let mut s = SCHED.lock();
+        let expired: Vec<Tid> = s.threads.iter()
+            .filter(|(_, t)| t.state == ThreadState::Sleeping && t.wake_at.map_or(false, |w| w <= now))
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in expired {
+            s.make_ready(tid);
+        }
+    }
+    schedule();
+}
+
+/// Gives up the CPU to the next ready thread.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Ends the running thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut s = SCHED.lock();
+        let cur = s.current;
+        if cur == s.idle {
+            panic!("idle thread exited");
+        }
+        s.threads.get_mut(&cur).expect("current thread vanished").state = ThreadState::Dead;
+    }
+    schedule();
+    unreachable!("dead thread was scheduled");
+}
+
+pub fn current() -> Tid {
+    with_sched(|s| s.current)
+}
+
+/// The process the running thread belongs to.
+pub fn current_proc() -> Option<proc::Pid> {
+    with_sched(|s| s.threads.get(&s.current).and_then(|t| t.proc))
+}
+
+/// Moves the running thread to the process `pid`.
+pub fn set_proc(pid: Option<proc::Pid>) {
+    with_sched(|s| {
+        let cur = s.current;
+        if let Some(t) = s.threads.get_mut(&cur) {
+            t.proc = pid;
+        }
+    })
+}
+
+/// The running thread's address space.
+pub fn address_space() -> Option<Arc<memory::AddressSpace>> {
+    with_sched(|s| s.threads.get(&s.current).and_then(|t| t.space.clone()))
+}
+
+/// Gives the running thread the address space `space` and loads it. The
+/// old one is freed if no other thread uses it.
+pub fn set_address_space(space: Arc<memory::AddressSpace>) {
+    let old = with_sched(|s| {
+        memory::switch_table(space.p4_frame());
+        let cur = s.current;
+        s.threads.get_mut(&cur).and_then(|t| t.space.replace(space))
+    });
+    drop(old);
+}
+
+/// Sleeps for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) {
+    let r = Rendez::new();
+    r.tsleep(|| false, ms);
+}
+
+/// A place for one thread to wait for a condition, as in Plan 9. The
+/// condition is checked with interrupts off, so a wakeup from an interrupt
+/// handler can't slip in between the check and going to sleep.
+#[derive(Debug)]
+pub struct Rendez {
+    waiter: Mutex<Option<Tid>>,
+}
+
+impl Rendez {
+    pub const fn new() -> Self {
+        Self {
+            waiter: Mutex::new(None)
+        }
+    }
+
+    /// Sleeps until `cond` returns true.
+    pub fn sleep<F>(&self, cond: F)
+        where F: Fn() -> bool
+    {
+        self.sleep_until(cond, None);
+    }
+
+    /// Sleeps until `cond` returns true or `ms` milliseconds have passed,
+    /// returning whether `cond` holds.
+    pub fn tsleep<F>(&self, cond: F, ms: u64) -> bool
+        where F: Fn() -> bool
+    {
+        let deadline = clock::ticks().saturating_add(clock::ms_to_ticks(ms));
+        self.sleep_until(cond, Some(deadline))
+    }
+
+    fn sleep_until<F>(&self, cond: F, deadline: Option<u64>) -> bool
+        where F: Fn() -> bool
+    {
+        interrupts::without_interrupts(|| {
+            loop {
+                if cond() {
+                    return true;
+                }
+                if let Some(d) = deadline {
+                    if clock::ticks() >= d {
+                        return false;
+                    }
+                }
+
+                {
+                    let mut s = SCHED.lock();
+                    let cur = s.current;
+                    if cur == s.idle {
+                        panic!("idle thread tried to sleep");
+                    }
+                    let mut waiter = self.waiter.lock();
+                    if waiter.is_some() {
+                        panic!("double sleep");
+                    }
+                    *waiter = Some(cur);
+                    let t = s.threads.get_mut(&cur).expect("current thread vanished");
+                    t.state = ThreadState::Sleeping;
+                    t.wake_at = deadline;
+                }
+                schedule();
+
+                // a timeout wakes us without going through wakeup
+                *self.waiter.lock() = None;
+            }
+        })
+    }
+
+    /// Wakes the thread sleeping here, if any. Returns whether there was one.
+    pub fn wakeup(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let tid = self.waiter.lock().take();
+            match tid {
+                Some(tid) => {
+                    SCHED.lock().make_ready(tid);
+                    true
+                }
+                None => false
+            }
+        })
+    }
+}
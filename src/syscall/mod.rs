@@ -0,0 +1,322 @@
+//! The system call entry point. User programs enter through `syscall` with
+//! the call number in rax and arguments in rdi, rsi, rdx, r10, r8 and r9;
+//! the result goes back in rax.
+
+mod sysfile;
+mod sysproc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::borrow::ToOwned;
+use core::str;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::registers::rflags::RFlags;
+use crate::{gdt, nine_p, proc};
+use crate::interrupts::Ureg;
+use crate::{USER_START, USER_END};
+
+// System call numbers, as in Plan 9's sys.h.
+pub const BIND: u64 = 2;
+pub const CHDIR: u64 = 3;
+pub const CLOSE: u64 = 4;
+pub const DUP: u64 = 5;
+pub const EXEC: u64 = 7;
+pub const EXITS: u64 = 8;
+pub const OPEN: u64 = 14;
+pub const SLEEP: u64 = 17;
+pub const RFORK: u64 = 19;
+pub const CREATE: u64 = 22;
+pub const FD2PATH: u64 = 23;
+pub const BRK_: u64 = 24;
+pub const REMOVE: u64 = 25;
+pub const NOTIFY: u64 = 28;
+pub const NOTED: u64 = 29;
+pub const UNMOUNT: u64 = 35;
+pub const SEEK: u64 = 39;
+pub const ERRSTR: u64 = 41;
+pub const STAT: u64 = 42;
+pub const FSTAT: u64 = 43;
+pub const WSTAT: u64 = 44;
+pub const FWSTAT: u64 = 45;
+pub const MOUNT: u64 = 46;
+pub const AWAIT: u64 = 47;
+pub const PREAD: u64 = 50;
+pub const PWRITE: u64 = 51;
+
+/// The vector Plan 9 gives system calls, for the Ureg of a process
+/// interrupted at one.
+const SYSCALL_VECTOR: u64 = 64;
+
+/// The longest string, such as a path, taken from user memory.
+const MAX_USER_STRING: usize = 4096;
+
+/// The most a read or write moves through the kernel at once. Larger reads
+/// come back short and larger writes go in pieces.
+const MAX_IO: u64 = 64 * 1024;
+
+const STAR: u32 = 0xC000_0081;
+const LSTAR: u32 = 0xC000_0082;
+const SFMASK: u32 = 0xC000_0084;
+
+extern "C" {
+    fn syscall_entry();
+    fn jump_to_user(entry: u64, stack: u64) -> !;
+    fn fork_return(frame: *const SyscallFrame) -> !;
+    fn ureg_return(ureg: *const Ureg) -> !;
+    static mut kernel_rsp: u64;
+}
+
+/// The user's registers as saved by `syscall_entry`, lowest address first.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    /// The user's rip, which `syscall` leaves in rcx.
+    pub rip: u64,
+    /// The user's rflags, which `syscall` leaves in r11.
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// The frame as a Ureg. rcx and r11 are whatever `syscall` left in
+    /// them.
+    pub fn ureg(&self) -> Ureg {
+        Ureg {
+            r15: self.r15,
+            r14: self.r14,
+            r13: self.r13,
+            r12: self.r12,
+            r11: self.rflags,
+            r10: self.r10,
+            r9: self.r9,
+            r8: self.r8,
+            rbp: self.rbp,
+            rdi: self.rdi,
+            rsi: self.rsi,
+            rdx: self.rdx,
+            rcx: self.rip,
+            rbx: self.rbx,
+            rax: self.rax,
+            vector: SYSCALL_VECTOR,
+            error: 0,
+            rip: self.rip,
+            cs: u64::from(gdt::USER_CODE_SELECTOR),
+            rflags: self.rflags,
+            rsp: self.rsp,
+            ss: u64::from(gdt::USER_DATA_SELECTOR),
+        }
+    }
+}
+
+/// Points STAR, LSTAR and SFMASK at our entry code and segments and turns
+/// on the `syscall` instruction.
+pub fn init() {
+    assert_has_not_been_called!("syscall::init must be called only once");
+
+    // SYSRET adds 8 to the base for the user stack segment and 16 for the
+    // user code segment, SYSCALL adds 8 to the kernel code segment for the
+    // kernel stack segment.
+    let star = (u64::from(gdt::KERNEL_DATA_SELECTOR | 3) << 48) | (u64::from(gdt::KERNEL_CODE_SELECTOR) << 32);
+    let sfmask = RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG;
+
+    unsafe {
+        Msr::new(STAR).write(star);
+        Msr::new(LSTAR).write(syscall_entry as u64);
+        Msr::new(SFMASK).write(sfmask.bits());
+
+        let mut efer = Efer::read();
+        efer.set(EferFlags::SYSTEM_CALL_EXTENSIONS, true);
+        Efer::write(efer);
+    }
+}
+
+/// Sets the kernel stack used when the running thread enters the kernel
+/// from user mode, whether by system call or by interrupt.
+pub fn set_kernel_stack(top: VirtAddr) {
+    gdt::set_kernel_stack(top);
+    unsafe {
+        kernel_rsp = top.as_u64();
+    }
+}
+
+/// Leaves the kernel for user code at `entry`, running on `stack`.
+pub fn enter_user(entry: VirtAddr, stack: VirtAddr) -> ! {
+    unsafe { jump_to_user(entry.as_u64(), stack.as_u64()) }
+}
+
+/// Returns to user mode with the registers in `frame`, which must be on
+/// the running thread's kernel stack.
+pub fn return_to_user(frame: &SyscallFrame) -> ! {
+    unsafe { fork_return(frame) }
+}
+
+/// Returns to user mode with every register restored from `ureg`, which
+/// must be on the running thread's kernel stack.
+pub fn return_to_ureg(ureg: &Ureg) -> ! {
+    unsafe { ureg_return(ureg) }
+}
+
+pub use self::sysproc::deliver_note;
+
+#[no_mangle]
+pub extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    interrupts::enable();
+    frame.rax = dispatch(frame);
+    interrupts::disable();
+
+    if proc::current().read().has_notes() {
+        let mut ureg = frame.ureg();
+        deliver_note(&mut ureg);
+        return_to_ureg(&ureg);
+    }
+}
+
+/// Runs a system call. Errors set the process's error string and return
+/// -1, as in Plan 9.
+fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    let a = frame.args();
+    let res = match frame.number() {
+        BIND => sysfile::bind(a[0], a[1], a[2]),
+        CHDIR => sysfile::chdir(a[0]),
+        CLOSE => sysfile::close(a[0]),
+        DUP => sysfile::dup(a[0], a[1]),
+        EXEC => sysproc::exec(a[0], a[1]),
+        EXITS => sysproc::exits(a[0]),
+        OPEN => sysfile::open(a[0], a[1]),
+        SLEEP => sysproc::sleep(a[0]),
+        RFORK => sysproc::rfork(frame, a[0]),
+        CREATE => sysfile::create(a[0], a[1], a[2]),
+        FD2PATH => sysfile::fd2path(a[0], a[1], a[2]),
+        BRK_ => sysproc::brk(a[0]),
+        REMOVE => sysfile::remove(a[0]),
+        NOTIFY => sysproc::notify(a[0]),
+        NOTED => sysproc::noted(a[0]),
+        UNMOUNT => sysfile::unmount(a[0], a[1]),
+        SEEK => sysfile::seek(a[0], a[1], a[2]),
+        ERRSTR => sysproc::errstr(a[0], a[1]),
+        STAT => sysfile::stat(a[0], a[1], a[2]),
+        FSTAT => sysfile::fstat(a[0], a[1], a[2]),
+        WSTAT => sysfile::wstat(a[0], a[1], a[2]),
+        FWSTAT => sysfile::fwstat(a[0], a[1], a[2]),
+        MOUNT => sysfile::mount(a[0], a[1], a[2], a[3], a[4]),
+        AWAIT => sysproc::await_child(a[0], a[1]),
+        PREAD => sysfile::pread(a[0], a[1], a[2], a[3]),
+        PWRITE => sysfile::pwrite(a[0], a[1], a[2], a[3]),
+        _ => Err(nine_p::DevError::Str("bad system call number".to_owned()))
+    };
+
+    match res {
+        Ok(ret) => ret,
+        Err(e) => {
+            proc::current().write().set_errstr(&e.description());
+            !0
+        }
+    }
+}
+
+fn bad_arg() -> nine_p::DevError {
+    nine_p::DevError::Str("bad arg in system call".to_owned())
+}
+
+fn bad_address() -> nine_p::DevError {
+    nine_p::DevError::Str("bad address in system call".to_owned())
+}
+
+/// Checks that `len` bytes at `addr` lie wholly in user space.
+fn check_user(addr: u64, len: u64) -> nine_p::Result<()> {
+    let end = addr.checked_add(len).ok_or_else(bad_address)?;
+    if addr < USER_START as u64 || end > USER_END as u64 {
+        return Err(bad_address());
+    }
+    Ok(())
+}
+
+// A fault on user memory the process hasn't mapped ends the process where
+// it stands, from the page fault handler, so no lock may be held while user
+// memory is touched. Calls that go to a device copy through kernel buffers
+// with copy_in and copy_out.
+
+fn user_slice<'a>(addr: u64, len: u64) -> nine_p::Result<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    check_user(addr, len)?;
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+fn user_slice_mut<'a>(addr: u64, len: u64) -> nine_p::Result<&'a mut [u8]> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    check_user(addr, len)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+/// Copies `len` bytes in from user memory.
+fn copy_in(addr: u64, len: u64) -> nine_p::Result<Vec<u8>> {
+    Ok(user_slice(addr, len)?.to_vec())
+}
+
+/// Copies as much of `data` as fits into the user buffer of `len` bytes at
+/// `addr`. Returns how much that was.
+fn copy_out(data: &[u8], addr: u64, len: u64) -> nine_p::Result<usize> {
+    let buf = user_slice_mut(addr, len)?;
+    let n = core::cmp::min(data.len(), buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    Ok(n)
+}
+
+/// Copies in a NUL terminated string from user memory.
+fn user_str(addr: u64) -> nine_p::Result<String> {
+    let mut len = 0;
+    loop {
+        if len == MAX_USER_STRING {
+            return Err(bad_arg());
+        }
+        check_user(addr + len as u64, 1)?;
+        if unsafe { *((addr + len as u64) as *const u8) } == 0 {
+            break;
+        }
+        len += 1;
+    }
+    match str::from_utf8(user_slice(addr, len as u64)?) {
+        Ok(s) => Ok(s.to_owned()),
+        Err(_) => Err(bad_arg())
+    }
+}
+
+/// Copies `s` into a user buffer of `len` bytes, truncated if need be and
+/// always NUL terminated.
+fn copy_out_str(s: &str, addr: u64, len: u64) -> nine_p::Result<()> {
+    let buf = user_slice_mut(addr, len)?;
+    if buf.is_empty() {
+        return Err(bad_arg());
+    }
+    let n = core::cmp::min(s.len(), buf.len() - 1);
+    buf[..n].copy_from_slice(&s.as_bytes()[..n]);
+    buf[n] = 0;
+    Ok(())
+}
@@ -0,0 +1,273 @@
+//! System calls on files and the namespace.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+use alloc::borrow::ToOwned;
+use spin::Mutex;
+use crate::{dev, mnt, namespace, nine_p, proc};
+use super::{MAX_IO, bad_arg, check_user, copy_in, copy_out, user_slice, user_slice_mut, user_str, copy_out_str};
+
+fn new_fd(chan: dev::Chan) -> nine_p::Result<u64> {
+    let fds = proc::current().read().fds();
+    let fd = fds.write().insert(Arc::new(Mutex::new(proc::Fd::new(chan))))?;
+    Ok(fd as u64)
+}
+
+fn get_fd(fd: u64) -> nine_p::Result<proc::FdRef> {
+    let fds = proc::current().read().fds();
+    let fd = fds.read().get(fd as usize);
+    fd
+}
+
+fn file_mode(mode: u64) -> nine_p::Result<nine_p::FileMode> {
+    if mode > 0xff {
+        return Err(bad_arg());
+    }
+    nine_p::FileMode::from_bits(mode as u8).map_err(|_| bad_arg())
+}
+
+/// Resolves a path from user memory against the current directory.
+fn user_path(addr: u64) -> nine_p::Result<String> {
+    let path = user_str(addr)?;
+    Ok(proc::current().read().abs_path(&path))
+}
+
+fn walk(path: &str) -> nine_p::Result<dev::Chan> {
+    let ns = proc::current().read().namespace();
+    let chan = ns.read().walk(path);
+    chan
+}
+
+pub fn open(name: u64, mode: u64) -> nine_p::Result<u64> {
+    let path = user_path(name)?;
+    let mode = file_mode(mode)?;
+    let ns = proc::current().read().namespace();
+    let chan = ns.read().open_file(&path, &mode)?;
+    new_fd(chan)
+}
+
+pub fn create(name: u64, mode: u64, perm: u64) -> nine_p::Result<u64> {
+    let path = user_path(name)?;
+    let mode = file_mode(mode)?;
+    let ns = proc::current().read().namespace();
+    let chan = ns.read().create(&path, perm as u32, &mode)?;
+    new_fd(chan)
+}
+
+pub fn close(fd: u64) -> nine_p::Result<u64> {
+    let fds = proc::current().read().fds();
+    fds.write().remove(fd as usize)?;
+    Ok(0)
+}
+
+pub fn dup(old: u64, new: u64) -> nine_p::Result<u64> {
+    let fds = proc::current().read().fds();
+    let mut fds = fds.write();
+    let fd = fds.get(old as usize)?;
+    if new as i32 == -1 {
+        Ok(fds.insert(fd)? as u64)
+    } else {
+        fds.insert_at(new as usize, fd)?;
+        Ok(new)
+    }
+}
+
+/// Reads into a user buffer. An offset of -1 reads from, and advances,
+/// the descriptor's own offset.
+pub fn pread(fd: u64, buf: u64, n: u64, offset: u64) -> nine_p::Result<u64> {
+    check_user(buf, n)?;
+    let n = core::cmp::min(n, MAX_IO);
+    let data = {
+        let fd = get_fd(fd)?;
+        let mut fd = fd.lock();
+        let use_fd_offset = offset as i64 == -1;
+        let offset = if use_fd_offset { fd.offset() } else { offset };
+
+        let mut data = match fd.chan().read(offset, n as usize) {
+            Ok(data) => data,
+            Err(nine_p::DevError::EOF) => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        data.truncate(n as usize);
+        if use_fd_offset {
+            fd.set_offset(offset + data.len() as u64);
+        }
+        data
+    };
+    Ok(copy_out(&data, buf, n)? as u64)
+}
+
+/// Writes from a user buffer, MAX_IO at a time. An offset of -1 writes at,
+/// and advances, the descriptor's own offset.
+pub fn pwrite(fd: u64, buf: u64, n: u64, offset: u64) -> nine_p::Result<u64> {
+    check_user(buf, n)?;
+    let fd_ref = get_fd(fd)?;
+    let use_fd_offset = offset as i64 == -1;
+    let mut done = 0;
+    loop {
+        let data = copy_in(buf + done, core::cmp::min(n - done, MAX_IO))?;
+        let mut fd = fd_ref.lock();
+        let at = if use_fd_offset { fd.offset() } else { offset + done };
+        let written = match fd.chan().write(at, &data) {
+            Ok(written) => written,
+            Err(e) if done == 0 => return Err(e),
+            // report what did go
+            Err(_) => return Ok(done)
+        };
+        if use_fd_offset {
+            fd.set_offset(at + written as u64);
+        }
+        done += written as u64;
+        // a device taking less than it was given is done
+        if done == n || written < data.len() {
+            return Ok(done);
+        }
+    }
+}
+
+/// Moves the descriptor's offset: `whence` 0 sets it, 1 adds to it and 2
+/// adds to the file's length. Returns the new offset.
+pub fn seek(fd: u64, n: u64, whence: u64) -> nine_p::Result<u64> {
+    let fd = get_fd(fd)?;
+    let mut fd = fd.lock();
+    let n = n as i64;
+    if fd.chan().is_dir() && !(whence == 0 && n == 0) {
+        return Err(nine_p::DevError::Str("seek in directory".to_owned()));
+    }
+
+    let base = match whence {
+        0 => 0,
+        1 => fd.offset() as i64,
+        2 => fd.chan().stat()?.length() as i64,
+        _ => return Err(bad_arg())
+    };
+    let offset = base.checked_add(n).ok_or_else(bad_arg)?;
+    if offset < 0 {
+        return Err(nine_p::DevError::Str("negative seek offset".to_owned()));
+    }
+    fd.set_offset(offset as u64);
+    Ok(offset as u64)
+}
+
+/// Copies a stat entry out. If the buffer is too small only the entry's
+/// size prefix is copied, so the caller can retry with a larger buffer.
+fn copy_out_stat(dir: &nine_p::dir::Dir, buf: u64, n: u64) -> nine_p::Result<u64> {
+    let stat = dir.as_bytes();
+    let buf = user_slice_mut(buf, n)?;
+    if buf.len() < stat.len() {
+        if buf.len() < 2 {
+            return Err(nine_p::DevError::Str("stat buffer too small".to_owned()));
+        }
+        buf[..2].copy_from_slice(&stat[..2]);
+        return Ok(2);
+    }
+    buf[..stat.len()].copy_from_slice(&stat);
+    Ok(stat.len() as u64)
+}
+
+pub fn stat(name: u64, buf: u64, n: u64) -> nine_p::Result<u64> {
+    let path = user_path(name)?;
+    let dir = walk(&path)?.stat()?;
+    copy_out_stat(&dir, buf, n)
+}
+
+pub fn fstat(fd: u64, buf: u64, n: u64) -> nine_p::Result<u64> {
+    let dir = get_fd(fd)?.lock().chan().stat()?;
+    copy_out_stat(&dir, buf, n)
+}
+
+pub fn wstat(name: u64, buf: u64, n: u64) -> nine_p::Result<u64> {
+    let path = user_path(name)?;
+    let dir = nine_p::dir::Dir::from_bytes(user_slice(buf, n)?)?;
+    walk(&path)?.wstat(&dir)?;
+    Ok(0)
+}
+
+pub fn fwstat(fd: u64, buf: u64, n: u64) -> nine_p::Result<u64> {
+    let dir = nine_p::dir::Dir::from_bytes(user_slice(buf, n)?)?;
+    get_fd(fd)?.lock().chan().wstat(&dir)?;
+    Ok(0)
+}
+
+pub fn remove(name: u64) -> nine_p::Result<u64> {
+    let path = user_path(name)?;
+    walk(&path)?.remove()?;
+    Ok(0)
+}
+
+pub fn chdir(name: u64) -> nine_p::Result<u64> {
+    let path = user_str(name)?;
+    proc::current().write().chdir(&path)?;
+    Ok(0)
+}
+
+pub fn fd2path(fd: u64, buf: u64, n: u64) -> nine_p::Result<u64> {
+    let path = get_fd(fd)?.lock().chan().path().to_owned();
+    copy_out_str(&path, buf, n)?;
+    Ok(0)
+}
+
+fn bind_flags(flag: u64) -> nine_p::Result<namespace::BindFlags> {
+    let flags = namespace::BindFlags::from_bits(flag as u32).ok_or_else(bad_arg)?;
+    if flags.contains(namespace::BindFlags::MORDER) {
+        return Err(bad_arg());
+    }
+    Ok(flags)
+}
+
+/// Checks that `old` exists and is the same kind of file as `new` is.
+fn check_mount_point(old: &str, new_is_dir: bool) -> nine_p::Result<()> {
+    if walk(old)?.is_dir() != new_is_dir {
+        return Err(nine_p::DevError::Str("inconsistent mount".to_owned()));
+    }
+    Ok(())
+}
+
+pub fn bind(name: u64, old: u64, flag: u64) -> nine_p::Result<u64> {
+    let flags = bind_flags(flag)?;
+    let new = user_path(name)?;
+    let old = user_path(old)?;
+    let p = proc::current();
+    if p.read().no_mount() && new.starts_with('#') {
+        return Err(nine_p::DevError::PermissionDenied);
+    }
+
+    check_mount_point(&old, walk(&new)?.is_dir())?;
+    let ns = p.read().namespace();
+    ns.write().bind(&old, &new, flags);
+    Ok(0)
+}
+
+/// Mounts the 9P server at the other end of `fd` on `old`. Authentication
+/// isn't supported, so `afd` must be -1.
+pub fn mount(fd: u64, afd: u64, old: u64, flag: u64, aname: u64) -> nine_p::Result<u64> {
+    let flags = bind_flags(flag)?;
+    let old = user_path(old)?;
+    let aname = if aname == 0 { "".to_owned() } else { user_str(aname)? };
+    if afd as i32 != -1 {
+        return Err(nine_p::DevError::Str("authentication not supported".to_owned()));
+    }
+    let p = proc::current();
+    if p.read().no_mount() {
+        return Err(nine_p::DevError::PermissionDenied);
+    }
+
+    let fd = get_fd(fd)?;
+    let new = format!("#M{}", mnt::new_session(fd, &aname)?);
+    check_mount_point(&old, walk(&new)?.is_dir())?;
+    let ns = p.read().namespace();
+    ns.write().bind(&old, &new, flags);
+    Ok(0)
+}
+
+/// Undoes a bind or mount of `name` on `old`, or everything on `old` if
+/// `name` is nil.
+pub fn unmount(name: u64, old: u64) -> nine_p::Result<u64> {
+    let name = if name == 0 { None } else { Some(user_path(name)?) };
+    let old = user_path(old)?;
+    let ns = proc::current().read().namespace();
+    ns.write().unmount(&old, name.as_ref().map(|n| n.as_str()))?;
+    Ok(0)
+}
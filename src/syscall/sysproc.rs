@@ -0,0 +1,217 @@
+//! System calls on processes.
+
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use core::{mem, ptr};
+use byteorder::{LittleEndian, ByteOrder};
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use crate::{exec, gdt, nine_p, proc, sched, USER_END};
+use crate::interrupts::Ureg;
+use super::{SyscallFrame, bad_arg, bad_address, check_user, user_slice, user_slice_mut, user_str, copy_out_str,
+            return_to_user, return_to_ureg};
+
+/// The most arguments exec will take.
+const MAX_ARGS: usize = 256;
+
+// What noted does when the handler is done, as in Plan 9's libc.h.
+const NCONT: u64 = 0;
+const NDFLT: u64 = 1;
+const NRSTR: u64 = 3;
+
+/// The bytes below the stack pointer that the interrupted code may still
+/// be using.
+const RED_ZONE: u64 = 128;
+
+/// Swaps the process's error string with the one in the user's buffer.
+pub fn errstr(buf: u64, n: u64) -> nine_p::Result<u64> {
+    let new = {
+        let buf = user_slice(buf, n)?;
+        let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..end]).into_owned()
+    };
+    let old = proc::current().write().swap_errstr(new);
+    copy_out_str(&old, buf, n)?;
+    Ok(0)
+}
+
+pub fn exits(msg: u64) -> nine_p::Result<u64> {
+    let msg = if msg == 0 { "".to_owned() } else { user_str(msg).unwrap_or_default() };
+    proc::exits(&msg);
+}
+
+pub fn sleep(ms: u64) -> nine_p::Result<u64> {
+    if ms as i64 <= 0 {
+        sched::yield_now();
+    } else {
+        sched::sleep_ms(ms);
+    }
+    Ok(0)
+}
+
+/// Without RFPROC changes the calling process's resource groups. With it
+/// starts a child, which returns to user mode from the same call with 0.
+pub fn rfork(frame: &SyscallFrame, flags: u64) -> nine_p::Result<u64> {
+    let flags = proc::RFork::from_bits(flags as u32).ok_or_else(bad_arg)?;
+
+    // a child gets a copy of our memory, or with RFMEM shares all of it
+    // but the stack
+    let space = if flags.contains(proc::RFork::RFPROC) {
+        match sched::address_space() {
+            Some(space) => {
+                let space = if flags.contains(proc::RFork::RFMEM) { space.share() } else { space.fork() };
+                Some(space.ok_or_else(|| nine_p::DevError::Str("no free memory".to_owned()))?)
+            }
+            None => None
+        }
+    } else {
+        None
+    };
+
+    let pid = proc::rfork(flags)?;
+    if flags.contains(proc::RFork::RFPROC) {
+        let mut child = *frame;
+        child.rax = 0;
+        sched::spawn(Some(pid), move || {
+            if let Some(space) = space {
+                sched::set_address_space(Arc::new(space));
+            }
+            let frame = child;
+            return_to_user(&frame);
+        });
+    }
+    Ok(pid)
+}
+
+/// Sets the note handler, or with 0 removes it.
+pub fn notify(handler: u64) -> nine_p::Result<u64> {
+    if handler != 0 {
+        check_user(handler, 1)?;
+    }
+    proc::current().write().set_notify(handler);
+    Ok(0)
+}
+
+/// Sets `ureg` up to enter the note handler with the oldest pending note,
+/// as handler(ureg, note). The registers it held are saved on the user
+/// stack for noted to restore. A note with no handler ends the process.
+pub fn deliver_note(ureg: &mut Ureg) {
+    let next = proc::current().write().take_note();
+    let (note, handler) = match next {
+        Some(next) => next,
+        None => return
+    };
+    if handler == 0 {
+        proc::exits(&note);
+    }
+
+    let ureg_size = mem::size_of::<Ureg>() as u64;
+    let ureg_addr = (ureg.rsp.wrapping_sub(RED_ZONE) & !0xf).wrapping_sub(ureg_size);
+    let note_addr = ureg_addr.wrapping_sub(proc::ERRMAX as u64);
+    // a return address of 0, the handler must leave through noted
+    let sp = note_addr.wrapping_sub(8);
+
+    let saved = match user_slice_mut(ureg_addr, ureg_size) {
+        Ok(buf) => buf.as_mut_ptr() as *mut Ureg,
+        Err(_) => proc::exits(&note)
+    };
+    unsafe { ptr::write_unaligned(saved, *ureg) };
+    if copy_out_str(&note, note_addr, proc::ERRMAX as u64).is_err() {
+        proc::exits(&note);
+    }
+    match user_slice_mut(sp, 8) {
+        Ok(buf) => LittleEndian::write_u64(buf, 0),
+        Err(_) => proc::exits(&note)
+    }
+
+    proc::current().write().set_notified(note, ureg_addr);
+    ureg.rip = handler;
+    ureg.rsp = sp;
+    ureg.rdi = ureg_addr;
+    ureg.rsi = note_addr;
+    ureg.rflags &= !(RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG).bits();
+}
+
+/// Leaves the note handler. NCONT and NRSTR go back to the registers saved
+/// for it, which the handler may have changed; NDFLT ends the process with
+/// the note.
+pub fn noted(arg: u64) -> nine_p::Result<u64> {
+    if arg != NCONT && arg != NRSTR && arg != NDFLT {
+        return Err(bad_arg());
+    }
+    let notified = proc::current().write().noted();
+    let (note, addr) = notified.ok_or_else(|| nine_p::DevError::Str("not in a note handler".to_owned()))?;
+    if arg == NDFLT {
+        proc::exits(&note);
+    }
+    drop(note);
+
+    let saved = user_slice(addr, mem::size_of::<Ureg>() as u64)?;
+    let mut ureg = unsafe { ptr::read_unaligned(saved.as_ptr() as *const Ureg) };
+    if check_user(ureg.rip, 1).is_err() {
+        return Err(bad_address());
+    }
+
+    // the handler may change the registers but not leave user mode
+    let user_flags = RFlags::CARRY_FLAG | RFlags::PARITY_FLAG | RFlags::AUXILIARY_CARRY_FLAG |
+        RFlags::ZERO_FLAG | RFlags::SIGN_FLAG | RFlags::DIRECTION_FLAG | RFlags::OVERFLOW_FLAG;
+    ureg.cs = u64::from(gdt::USER_CODE_SELECTOR);
+    ureg.ss = u64::from(gdt::USER_DATA_SELECTOR);
+    ureg.rflags = (ureg.rflags & user_flags.bits()) | RFlags::INTERRUPT_FLAG.bits();
+
+    interrupts::disable();
+    deliver_note(&mut ureg);
+    return_to_ureg(&ureg)
+}
+
+/// Copies in the nil terminated array of argument pointers at `argv`.
+fn user_args(argv: u64) -> nine_p::Result<Vec<String>> {
+    let mut args = Vec::new();
+    loop {
+        if args.len() == MAX_ARGS {
+            return Err(nine_p::DevError::Str("arg list too long".to_owned()));
+        }
+        let ptr = user_slice(argv + 8 * args.len() as u64, 8)?;
+        let ptr = LittleEndian::read_u64(ptr);
+        if ptr == 0 {
+            return Ok(args);
+        }
+        args.push(user_str(ptr)?);
+    }
+}
+
+/// Replaces the process's image. Only returns on failure.
+pub fn exec(name: u64, argv: u64) -> nine_p::Result<u64> {
+    let path = user_str(name)?;
+    let path = proc::current().read().abs_path(&path);
+    let args = user_args(argv)?;
+    Err(exec::exec(path, args))
+}
+
+/// Moves the end of the bss to `addr`, for the heap. The memory gained
+/// reads as zeros.
+pub fn brk(addr: u64) -> nine_p::Result<u64> {
+    check_user(addr, 1)?;
+    let space = sched::address_space().ok_or_else(bad_address)?;
+    // the end is rounded up to a page, which must still be a user address
+    if addr > USER_END as u64 - 4096 || !space.brk(VirtAddr::new(addr)) {
+        return Err(nine_p::DevError::Str("brk out of range".to_owned()));
+    }
+    Ok(0)
+}
+
+/// Waits for a child to exit and copies out its wait message.
+pub fn await_child(buf: u64, n: u64) -> nine_p::Result<u64> {
+    let wait = proc::current().read().wait_queue();
+    let msg = wait.await_child()?;
+    let buf = user_slice_mut(buf, n)?;
+    let len = core::cmp::min(msg.len(), buf.len());
+    buf[..len].copy_from_slice(&msg.as_bytes()[..len]);
+    if len < buf.len() {
+        buf[len] = 0;
+    }
+    Ok(len as u64)
+}
@@ -0,0 +1,385 @@
+//! The 16550 serial port COM1, and `#t`, which serves it as `eia0` and
+//! `eia0ctl` as Plan 9 does. The port mirrors everything printed and its
+//! input goes to the console, except while `eia0` is open for reading.
+
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::format;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::{cons, dev, nine_p, sched};
+
+const COM1: u16 = 0x3f8;
+
+/// The UART's clock divided by 16; the baud rate divisor divides this.
+const MAX_BAUD: u32 = 115_200;
+
+// Registers, as offsets from the port's base.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const IER_RECEIVE: u8 = 1 << 0;
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_PARITY: u8 = 1 << 3;
+const LCR_EVEN_PARITY: u8 = 1 << 4;
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+/// Enable and clear the FIFOs, interrupting at 14 bytes.
+const FCR_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2, which lets the UART's interrupts through to the PIC.
+const MCR_ON: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// How much input is kept for readers of `eia0`.
+const INPUT_SIZE: usize = 1024;
+
+struct Uart {
+    base: u16,
+    present: bool,
+    baud: u32,
+    bits: u8,
+    /// 'n', 'e' or 'o'.
+    parity: char,
+    stop: u8,
+    input: [u8; INPUT_SIZE],
+    input_start: usize,
+    input_len: usize,
+    /// How many opens of `eia0` are reading it. While there are any the
+    /// port's input goes to them rather than to the console.
+    readers: usize,
+    /// The bytes so far of a UTF-8 character on its way to the console.
+    partial: [u8; 4],
+    partial_len: usize,
+}
+
+impl Uart {
+    const fn new(base: u16) -> Uart {
+        Uart {
+            base,
+            present: false,
+            baud: MAX_BAUD,
+            bits: 8,
+            parity: 'n',
+            stop: 1,
+            input: [0; INPUT_SIZE],
+            input_start: 0,
+            input_len: 0,
+            readers: 0,
+            partial: [0; 4],
+            partial_len: 0,
+        }
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        let mut port: Port<u8> = Port::new(self.base + reg);
+        unsafe { port.read() }
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        let mut port: Port<u8> = Port::new(self.base + reg);
+        unsafe { port.write(value) }
+    }
+
+    /// Checks the UART is there through its scratch register, then sets it
+    /// up to interrupt on input.
+    fn init(&mut self) {
+        self.write_reg(SCRATCH, 0xae);
+        if self.read_reg(SCRATCH) != 0xae {
+            return;
+        }
+        self.present = true;
+        self.write_reg(INTERRUPT_ENABLE, 0);
+        self.set_line();
+        self.write_reg(FIFO_CONTROL, FCR_ENABLE);
+        self.write_reg(MODEM_CONTROL, MCR_ON);
+        self.write_reg(INTERRUPT_ENABLE, IER_RECEIVE);
+    }
+
+    /// Programs the baud rate, word length, parity and stop bits.
+    fn set_line(&self) {
+        let divisor = (MAX_BAUD / self.baud) as u16;
+        let mut lcr = self.bits - 5;
+        if self.stop == 2 {
+            lcr |= LCR_TWO_STOP_BITS;
+        }
+        match self.parity {
+            'e' => lcr |= LCR_PARITY | LCR_EVEN_PARITY,
+            'o' => lcr |= LCR_PARITY,
+            _ => {}
+        }
+        self.write_reg(LINE_CONTROL, LCR_DLAB);
+        self.write_reg(DATA, divisor as u8);
+        self.write_reg(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write_reg(LINE_CONTROL, lcr);
+    }
+
+    /// Sends a byte, waiting for room in the transmitter.
+    fn putc(&self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        while self.read_reg(LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        self.write_reg(DATA, byte);
+    }
+
+    fn getc(&self) -> Option<u8> {
+        if self.present && self.read_reg(LINE_STATUS) & LSR_DATA_READY != 0 {
+            Some(self.read_reg(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Adds a byte to a UTF-8 character for the console, returning the
+    /// character once it's whole. Bytes that can't start or continue one
+    /// are dropped.
+    fn decode(&mut self, byte: u8) -> Option<char> {
+        self.partial[self.partial_len] = byte;
+        self.partial_len += 1;
+        match core::str::from_utf8(&self.partial[..self.partial_len]) {
+            Ok(s) => {
+                self.partial_len = 0;
+                s.chars().next()
+            }
+            Err(e) => {
+                if e.error_len().is_some() {
+                    self.partial_len = 0;
+                }
+                None
+            }
+        }
+    }
+
+    fn push_input(&mut self, byte: u8) {
+        if self.input_len < INPUT_SIZE {
+            self.input[(self.input_start + self.input_len) % INPUT_SIZE] = byte;
+            self.input_len += 1;
+        }
+    }
+
+    fn pop_input(&mut self) -> Option<u8> {
+        if self.input_len == 0 {
+            return None;
+        }
+        let byte = self.input[self.input_start];
+        self.input_start = (self.input_start + 1) % INPUT_SIZE;
+        self.input_len -= 1;
+        Some(byte)
+    }
+
+    /// The settings in the form eia0ctl takes them.
+    fn ctl_text(&self) -> String {
+        format!("b{} l{} p{} s{}\n", self.baud, self.bits, self.parity, self.stop)
+    }
+
+    /// Applies control messages, such as `b9600 pe`, to the line.
+    fn ctl(&mut self, msgs: &str) -> nine_p::Result<()> {
+        let bad = || nine_p::DevError::Str("bad control message".to_owned());
+        for msg in msgs.split_whitespace() {
+            if !msg.is_char_boundary(1) {
+                return Err(bad());
+            }
+            let (cmd, arg) = msg.split_at(1);
+            match cmd {
+                "b" => {
+                    let baud = arg.parse::<u32>().map_err(|_| bad())?;
+                    if baud == 0 || baud > MAX_BAUD || MAX_BAUD % baud != 0 {
+                        return Err(bad());
+                    }
+                    self.baud = baud;
+                }
+                "l" => {
+                    let bits = arg.parse::<u8>().map_err(|_| bad())?;
+                    if bits < 5 || bits > 8 {
+                        return Err(bad());
+                    }
+                    self.bits = bits;
+                }
+                "p" if arg == "n" || arg == "e" || arg == "o" => self.parity = arg.chars().next().unwrap(),
+                "s" if arg == "1" || arg == "2" => self.stop = if arg == "1" { 1 } else { 2 },
+                _ => return Err(bad())
+            }
+        }
+        self.set_line();
+        Ok(())
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.putc(b'\r');
+            }
+            self.putc(byte);
+        }
+        Ok(())
+    }
+}
+
+static UART: Mutex<Uart> = Mutex::new(Uart::new(COM1));
+static INPUT_READY: sched::Rendez = sched::Rendez::new();
+/// Held by the one reader waiting on `INPUT_READY`.
+static READER: Mutex<()> = Mutex::new(());
+
+/// Sets up COM1, if there is one. Its interrupt still has to be enabled
+/// at the PIC.
+pub fn init() {
+    assert_has_not_been_called!("uart::init must be called only once");
+    interrupts::without_interrupts(|| UART.lock().init());
+}
+
+/// Copies printed text to the port. Called with interrupts off.
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = UART.lock().write_fmt(args);
+}
+
+/// Takes the port's input, from its interrupt handler.
+pub fn interrupt() {
+    loop {
+        let (c, wake) = {
+            let mut uart = UART.lock();
+            let byte = match uart.getc() {
+                Some(byte) => byte,
+                None => return
+            };
+            if uart.readers > 0 {
+                uart.push_input(byte);
+                (None, true)
+            } else {
+                (uart.decode(byte), false)
+            }
+        };
+        // the console echoes, which needs the port unlocked
+        if let Some(c) = c {
+            cons::kbd_putc(c);
+        }
+        if wake {
+            INPUT_READY.wakeup();
+        }
+    }
+}
+
+/// Reads the port's input into `buf`, waiting for some.
+fn read_input(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    let _reader = READER.lock();
+    INPUT_READY.sleep(|| UART.lock().input_len > 0);
+
+    interrupts::without_interrupts(|| {
+        let mut uart = UART.lock();
+        let mut n = 0;
+        while n < buf.len() {
+            match uart.pop_input() {
+                Some(byte) => buf[n] = byte,
+                None => break
+            }
+            n += 1;
+        }
+        n
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartFile {
+    Data,
+    Ctl,
+}
+
+/// The files in the device's directory, with their permissions.
+const UART_FILES: &[(&str, UartFile, u32)] = &[
+    ("eia0", UartFile::Data, 0o660),
+    ("eia0ctl", UartFile::Ctl, 0o660),
+];
+
+
+/// `eia0`: the bytes sent and received, untranslated. Opening it for
+/// reading takes the port's input from the console until it's closed.
+#[derive(Debug)]
+struct Data {
+    reading: bool,
+}
+
+impl Data {
+    fn new(reading: bool) -> Data {
+        if reading {
+            interrupts::without_interrupts(|| UART.lock().readers += 1);
+        }
+        Data { reading }
+    }
+}
+
+impl nine_p::FileRWC for Data {
+    fn read_at(&mut self, _pos: u64, buf: &mut [u8]) -> nine_p::Result<usize> {
+        Ok(read_input(buf))
+    }
+
+    fn write_at(&mut self, _pos: u64, buf: &[u8]) -> nine_p::Result<usize> {
+        interrupts::without_interrupts(|| {
+            let uart = UART.lock();
+            buf.iter().for_each(|&b| uart.putc(b));
+        });
+        Ok(buf.len())
+    }
+}
+
+impl Drop for Data {
+    fn drop(&mut self) {
+        if self.reading {
+            interrupts::without_interrupts(|| UART.lock().readers -= 1);
+        }
+    }
+}
+
+/// `eia0ctl`: reads give the line settings, writes change them.
+#[derive(Debug)]
+struct Ctl;
+
+impl nine_p::FileRWC for Ctl {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> nine_p::Result<usize> {
+        let text = interrupts::without_interrupts(|| UART.lock().ctl_text());
+        let mut text = text.as_bytes();
+        nine_p::FileRWC::read_at(&mut text, pos, buf)
+    }
+
+    fn write_at(&mut self, _pos: u64, buf: &[u8]) -> nine_p::Result<usize> {
+        let msgs = core::str::from_utf8(buf).map_err(|_| nine_p::DevError::Str("bad utf8".to_owned()))?;
+        interrupts::without_interrupts(|| UART.lock().ctl(msgs))?;
+        Ok(buf.len())
+    }
+}
+
+impl dev::DevFile for UartFile {
+    const NAME: char = 't';
+    const DESCRIPTION: &'static str = "uart";
+    const FILES: &'static [(&'static str, UartFile, u32)] = UART_FILES;
+
+    fn open(self, access: nine_p::FileAccessMode) -> Box<dyn nine_p::FileRWC> {
+        match self {
+            UartFile::Data => {
+                let reading = access == nine_p::FileAccessMode::Read || access == nine_p::FileAccessMode::ReadWrite;
+                Box::new(Data::new(reading))
+            }
+            UartFile::Ctl => Box::new(Ctl),
+        }
+    }
+
+    fn blocks(self) -> bool {
+        self == UartFile::Data
+    }
+}
+
+pub type UartServer = dev::DevDir<UartFile>;
@@ -2,6 +2,8 @@ use volatile::Volatile;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use core::fmt;
+use crate::kmesg;
+use crate::framebuffer::{self, Framebuffer};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -45,82 +47,517 @@ struct ScreenChar {
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
+/// The largest screen, in cells, the console uses: 1920x1080 in 8x16
+/// cells. Only this much of a bigger framebuffer is drawn on.
+const MAX_WIDTH: usize = 240;
+const MAX_HEIGHT: usize = 68;
+
+/// How many lines scrolled off the top are kept to look back at.
+const SCROLLBACK_LINES: usize = 500;
+const TAB_WIDTH: usize = 8;
+/// The most parameters kept from an escape sequence; later ones are ignored.
+const MAX_PARAMS: usize = 8;
+
+const DEFAULT_FOREGROUND: Color = Color::LightGray;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+/// The VGA colors for ANSI colors 0 to 7; the bright ones are 8 on.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
+// the CRTC registers holding the hardware cursor's position
+const CRTC_ADDR: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_HIGH: u8 = 0x0e;
+const CURSOR_LOW: u8 = 0x0f;
+
+const EMPTY: ScreenChar = ScreenChar {
+    ascii_character: 0,
+    color_code: ColorCode(0),
+};
+
+static mut HISTORY: [[ScreenChar; MAX_WIDTH]; SCROLLBACK_LINES] = [[EMPTY; MAX_WIDTH]; SCROLLBACK_LINES];
+static mut SAVED_SCREEN: [[ScreenChar; MAX_WIDTH]; MAX_HEIGHT] = [[EMPTY; MAX_WIDTH]; MAX_HEIGHT];
+static mut FRAMEBUFFER_CELLS: [[ScreenChar; MAX_WIDTH]; MAX_HEIGHT] = [[EMPTY; MAX_WIDTH]; MAX_HEIGHT];
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// What the console draws on.
+enum Screen {
+    /// The VGA text buffer.
+    Text(&'static mut Buffer),
+    /// A framebuffer, with the cells drawn on it kept in `cells` as they
+    /// can't be read back from the pixels.
+    Graphic {
+        framebuffer: Framebuffer,
+        cells: &'static mut [[ScreenChar; MAX_WIDTH]; MAX_HEIGHT],
+        /// The cell drawn as the cursor.
+        cursor: Option<(usize, usize)>,
+    },
+}
+
+impl Screen {
+    fn read(&self, row: usize, col: usize) -> ScreenChar {
+        match self {
+            Screen::Text(buffer) => buffer.chars[row][col].read(),
+            Screen::Graphic { cells, .. } => cells[row][col],
+        }
+    }
+
+    fn write(&mut self, row: usize, col: usize, character: ScreenChar) {
+        match self {
+            Screen::Text(buffer) => buffer.chars[row][col].write(character),
+            Screen::Graphic { framebuffer, cells, .. } => {
+                cells[row][col] = character;
+                draw_cell(framebuffer, row, col, character, false);
+            }
+        }
+    }
+
+    /// Moves rows 1 to `height` up one, leaving the last as it was.
+    fn scroll(&mut self, width: usize, height: usize) {
+        match self {
+            Screen::Text(buffer) => {
+                for row in 1..height {
+                    for col in 0..width {
+                        let character = buffer.chars[row][col].read();
+                        buffer.chars[row - 1][col].write(character);
+                    }
+                }
+            }
+            Screen::Graphic { framebuffer, cells, cursor } => {
+                // take the cursor off first, or it moves up with the text
+                if let Some((row, col)) = cursor.take() {
+                    draw_cell(framebuffer, row, col, cells[row][col], false);
+                }
+                framebuffer.scroll();
+                for row in 1..height {
+                    cells[row - 1] = cells[row];
+                }
+            }
+        }
+    }
+
+    /// Shows the cursor at `position`, or hides it for None.
+    fn set_cursor(&mut self, position: Option<(usize, usize)>) {
+        use x86_64::instructions::port::Port;
+
+        match self {
+            Screen::Text(_) => {
+                let position = position.map_or(BUFFER_HEIGHT * BUFFER_WIDTH, |(row, col)| row * BUFFER_WIDTH + col);
+                let mut addr: Port<u8> = Port::new(CRTC_ADDR);
+                let mut data: Port<u8> = Port::new(CRTC_DATA);
+                unsafe {
+                    addr.write(CURSOR_LOW);
+                    data.write(position as u8);
+                    addr.write(CURSOR_HIGH);
+                    data.write((position >> 8) as u8);
+                }
+            }
+            Screen::Graphic { framebuffer, cells, cursor } => {
+                if let Some((row, col)) = cursor.take() {
+                    draw_cell(framebuffer, row, col, cells[row][col], false);
+                }
+                if let Some((row, col)) = position {
+                    draw_cell(framebuffer, row, col, cells[row][col], true);
+                }
+                *cursor = position;
+            }
+        }
+    }
+
+    /// The byte to put on the screen for character `c`.
+    fn glyph(&self, c: u32) -> u8 {
+        match (self, c) {
+            (_, 0x20..=0x7e) => c as u8,
+            // the font has Latin-1
+            (Screen::Graphic { .. }, 0xa0..=0xff) => c as u8,
+            (Screen::Graphic { .. }, _) => framebuffer::MISSING_GLYPH,
+            (Screen::Text(_), _) => 0xfe,
+        }
+    }
+}
+
+/// Draws `character` in its colors, or the other way round for the cursor.
+fn draw_cell(framebuffer: &mut Framebuffer, row: usize, col: usize, character: ScreenChar, inverse: bool) {
+    let ColorCode(color) = character.color_code;
+    // the top bit is blink on the text screen
+    let (foreground, background) = (color & 0xf, color >> 4 & 0x7);
+    let (foreground, background) = if inverse { (background, foreground) } else { (foreground, background) };
+    framebuffer.draw_cell(row, col, character.ascii_character, foreground, background);
+}
+
+/// The lines scrolled off the top of the screen, oldest first.
+struct History {
+    lines: &'static mut [[ScreenChar; MAX_WIDTH]; SCROLLBACK_LINES],
+    start: usize,
+    len: usize,
+}
+
+impl History {
+    /// Adds a line, dropping the oldest if full.
+    fn push(&mut self, line: [ScreenChar; MAX_WIDTH]) {
+        self.lines[(self.start + self.len) % SCROLLBACK_LINES] = line;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    fn line(&self, i: usize) -> [ScreenChar; MAX_WIDTH] {
+        self.lines[(self.start + i) % SCROLLBACK_LINES]
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+/// Where the writer is in an escape sequence.
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    /// Just after an ESC.
+    Esc,
+    /// In a control sequence, ESC [, collecting its numeric parameters.
+    /// `count` is the index of the one being read.
+    Csi { params: [usize; MAX_PARAMS], count: usize },
+}
+
+/// Writes to the screen, understanding the control characters and a
+/// subset of the ANSI escape sequences a VT100 does: SGR colors, cursor
+/// movement and erasing.
 pub struct Writer {
     column_position: usize,
     cur_row: usize,
+    /// The size of the screen in cells.
+    width: usize,
+    height: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    foreground: u8,
+    background: u8,
+    bold: bool,
+    screen: Screen,
+    history: History,
+    /// How many lines back from the live screen is being shown, 0 when
+    /// it's the live screen.
+    view: usize,
+    /// The live screen, kept while the history is shown.
+    saved: &'static mut [[ScreenChar; MAX_WIDTH]; MAX_HEIGHT],
+    escape: Escape,
+    /// The UTF-8 sequence being read: the bits so far and how many bytes
+    /// are still to come.
+    utf8: u32,
+    utf8_left: usize,
 }
 
 impl Writer {
     fn new_line(&mut self) {
         self.column_position = 0;
         self.cur_row += 1;
-        if self.cur_row >= BUFFER_HEIGHT {
-            self.cur_row = BUFFER_HEIGHT - 1;
-            for row in 1..BUFFER_HEIGHT {
-                for col in 0..BUFFER_WIDTH {
-                    let character = self.buffer.chars[row][col].read();
-                    self.buffer.chars[row - 1][col].write(character);
-                }
+        if self.cur_row >= self.height {
+            self.cur_row = self.height - 1;
+            let mut line = [EMPTY; MAX_WIDTH];
+            for col in 0..self.width {
+                line[col] = self.screen.read(0, col);
             }
-            self.clear_row(BUFFER_HEIGHT - 1);
+            self.history.push(line);
+            self.screen.scroll(self.width, self.height);
+            self.clear_row(self.height - 1);
         }
     }
 
     fn clear_row(&mut self, row: usize) {
+        self.clear_cells(row, 0, self.width);
+    }
+
+    /// Blanks the columns from `start` up to `end` of `row` in the current
+    /// background color.
+    fn clear_cells(&mut self, row: usize, start: usize, end: usize) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in start..end {
+            self.screen.write(row, col, blank);
         }
     }
 
+    /// Puts a character at the cursor, wrapping to the next line first if
+    /// the last one is full.
+    fn put_char(&mut self, byte: u8) {
+        if self.column_position >= self.width {
+            self.new_line();
+        }
+
+        let row = self.cur_row;
+        let col = self.column_position;
+
+        let color_code = self.color_code;
+        self.screen.write(row, col, ScreenChar {
+            ascii_character: byte,
+            color_code,
+        });
+        self.column_position += 1;
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        match self.escape {
+            Escape::None => self.control_char(byte),
+            Escape::Esc => {
+                self.escape = match byte {
+                    b'[' => Escape::Csi { params: [0; MAX_PARAMS], count: 0 },
+                    _ => Escape::None,
+                };
+            }
+            Escape::Csi { mut params, mut count } => match byte {
+                b'0'..=b'9' => {
+                    params[count] = params[count].saturating_mul(10).saturating_add((byte - b'0') as usize);
+                    self.escape = Escape::Csi { params, count };
+                }
+                b';' => {
+                    count = (count + 1).min(MAX_PARAMS - 1);
+                    self.escape = Escape::Csi { params, count };
+                }
+                // the final byte
+                0x40..=0x7e => {
+                    self.escape = Escape::None;
+                    self.control_sequence(byte, &params[..=count]);
+                }
+                // private and intermediate bytes, such as the ? of ESC [ ? 25 h
+                _ => {}
+            },
+        }
+    }
+
+    fn control_char(&mut self, byte: u8) {
+        if byte & 0xc0 != 0x80 {
+            // a sequence cut short is dropped
+            self.utf8_left = 0;
+        }
         match byte {
+            0x20..=0x7e => self.put_char(byte),
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+            b'\r' => self.column_position = 0,
+            // backspace moves back; erasing is up to whoever wrote it
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
+            b'\t' => loop {
+                self.put_char(b' ');
+                if self.column_position % TAB_WIDTH == 0 {
+                    break;
                 }
+            },
+            0x1b => self.escape = Escape::Esc,
+            0x80..=0xbf if self.utf8_left > 0 => {
+                self.utf8 = self.utf8 << 6 | u32::from(byte & 0x3f);
+                self.utf8_left -= 1;
+                if self.utf8_left == 0 {
+                    let glyph = self.screen.glyph(self.utf8);
+                    self.put_char(glyph);
+                }
+            }
+            0xc0..=0xdf => self.start_utf8(byte & 0x1f, 1),
+            0xe0..=0xef => self.start_utf8(byte & 0x0f, 2),
+            0xf0..=0xf7 => self.start_utf8(byte & 0x07, 3),
+            // bell, other controls and stray bytes
+            _ => {}
+        }
+    }
+
+    fn start_utf8(&mut self, bits: u8, left: usize) {
+        self.utf8 = u32::from(bits);
+        self.utf8_left = left;
+    }
+
+    /// Acts on ESC [ `params` `command`. A parameter left out reads as 0.
+    fn control_sequence(&mut self, command: u8, params: &[usize]) {
+        // movement by 0 means by 1
+        let n = params[0].max(1);
+        let column = self.column_position.min(self.width - 1);
+        match command {
+            b'A' => self.cur_row = self.cur_row.saturating_sub(n),
+            b'B' => self.cur_row = (self.cur_row + n).min(self.height - 1),
+            b'C' => self.column_position = (column + n).min(self.width - 1),
+            b'D' => self.column_position = column.saturating_sub(n),
+            b'H' | b'f' => {
+                // 1-based, row first
+                let col = params.get(1).cloned().unwrap_or(0).max(1);
+                self.cur_row = params[0].max(1).min(self.height) - 1;
+                self.column_position = col.min(self.width) - 1;
+            }
+            b'J' => self.erase_display(params[0]),
+            b'K' => self.erase_line(params[0]),
+            b'm' => self.select_graphic_rendition(params),
+            _ => {}
+        }
+    }
+
+    /// Erases from the cursor to the end of the line for mode 0, from the
+    /// start of the line to the cursor for 1, and the whole line for 2.
+    fn erase_line(&mut self, mode: usize) {
+        let row = self.cur_row;
+        let column = self.column_position.min(self.width - 1);
+        match mode {
+            0 => self.clear_cells(row, column, self.width),
+            1 => self.clear_cells(row, 0, column + 1),
+            2 => self.clear_row(row),
+            _ => {}
+        }
+    }
 
-                let row = self.cur_row;
-                let col = self.column_position;
+    /// Erases from the cursor to the end of the screen for mode 0, from the
+    /// start to the cursor for 1, and the whole screen for 2. Mode 3, as in
+    /// xterm, also forgets the scrollback. The cursor stays put.
+    fn erase_display(&mut self, mode: usize) {
+        match mode {
+            0 => {
+                self.erase_line(0);
+                (self.cur_row + 1..self.height).for_each(|row| self.clear_row(row));
+            }
+            1 => {
+                (0..self.cur_row).for_each(|row| self.clear_row(row));
+                self.erase_line(1);
+            }
+            2 | 3 => {
+                (0..self.height).for_each(|row| self.clear_row(row));
+                if mode == 3 {
+                    self.history.clear();
+                }
+            }
+            _ => {}
+        }
+    }
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1;
+    fn select_graphic_rendition(&mut self, params: &[usize]) {
+        for &param in params {
+            match param {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND as u8;
+                    self.background = DEFAULT_BACKGROUND as u8;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.foreground = ANSI_COLORS[param - 30] as u8,
+                39 => self.foreground = DEFAULT_FOREGROUND as u8,
+                40..=47 => self.background = ANSI_COLORS[param - 40] as u8,
+                49 => self.background = DEFAULT_BACKGROUND as u8,
+                90..=97 => self.foreground = ANSI_COLORS[param - 90] as u8 | 8,
+                // the top bit of the background is blink, so bright
+                // backgrounds come out as the plain ones
+                100..=107 => self.background = ANSI_COLORS[param - 100] as u8,
+                _ => {}
             }
         }
+        let foreground = if self.bold { self.foreground | 8 } else { self.foreground };
+        self.color_code = ColorCode(self.background << 4 | foreground);
     }
 
     pub fn clear_screen(&mut self) {
+        self.follow();
         self.cur_row = 0;
         self.column_position = 0;
-        for row in 0..BUFFER_HEIGHT {
+        for row in 0..self.height {
             self.clear_row(row);
         }
+        self.update_cursor();
     }
 
     pub fn write_string(&mut self, s: &str) {
+        self.follow();
         for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+            self.write_byte(byte);
+        }
+        self.update_cursor();
+    }
+
+    /// Moves the console from the text screen to `framebuffer`. The
+    /// scrollback is forgotten along with the text screen.
+    pub fn use_framebuffer(&mut self, framebuffer: Framebuffer) {
+        let (width, height) = framebuffer.cells();
+        self.width = width.min(MAX_WIDTH);
+        self.height = height.min(MAX_HEIGHT);
+        self.screen = Screen::Graphic {
+            framebuffer,
+            cells: unsafe { &mut FRAMEBUFFER_CELLS },
+            cursor: None,
+        };
+        self.view = 0;
+        self.history.clear();
+        self.clear_screen();
+    }
+
+    /// Shows the history further back, as for Shift+PgUp.
+    pub fn scroll_back(&mut self) {
+        if self.history.len == 0 {
+            return;
+        }
+        if self.view == 0 {
+            for row in 0..self.height {
+                for col in 0..self.width {
+                    self.saved[row][col] = self.screen.read(row, col);
+                }
+            }
+        }
+        self.view = (self.view + self.height / 2).min(self.history.len);
+        self.show();
+    }
+
+    /// Moves the view back towards the live screen, as for Shift+PgDn.
+    pub fn scroll_forward(&mut self) {
+        if self.view > 0 {
+            self.view = self.view.saturating_sub(self.height / 2);
+            self.show();
+        }
+    }
+
+    /// Goes back to the live screen, for new output.
+    fn follow(&mut self) {
+        if self.view > 0 {
+            self.view = 0;
+            self.show();
+        }
+    }
+
+    /// Draws the screen `view` lines back: the history followed by the
+    /// saved live screen.
+    fn show(&mut self) {
+        let first = self.history.len - self.view;
+        for row in 0..self.height {
+            let line = first + row;
+            let chars = if line < self.history.len {
+                self.history.line(line)
+            } else {
+                self.saved[line - self.history.len]
+            };
+            for col in 0..self.width {
+                self.screen.write(row, col, chars[col]);
             }
         }
+        self.update_cursor();
+    }
+
+    /// Moves the cursor to where the next character goes, or hides it
+    /// while the history is shown.
+    fn update_cursor(&mut self) {
+        let position = if self.view > 0 {
+            None
+        } else {
+            Some((self.cur_row, self.column_position.min(self.width - 1)))
+        };
+        self.screen.set_cursor(position);
     }
 }
 
@@ -135,8 +572,23 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         cur_row: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black, false),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        width: BUFFER_WIDTH,
+        height: BUFFER_HEIGHT,
+        color_code: ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND, false),
+        foreground: DEFAULT_FOREGROUND as u8,
+        background: DEFAULT_BACKGROUND as u8,
+        bold: false,
+        screen: Screen::Text(unsafe { &mut *(0xb8000 as *mut Buffer) }),
+        history: History {
+            lines: unsafe { &mut HISTORY },
+            start: 0,
+            len: 0,
+        },
+        view: 0,
+        saved: unsafe { &mut SAVED_SCREEN },
+        escape: Escape::None,
+        utf8: 0,
+        utf8_left: 0,
     });
 }
 
@@ -153,10 +605,30 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    _log(kmesg::Level::Info, args);
+}
+
+/// Prints to the screen and the serial port, and adds to the kernel log.
+#[doc(hidden)]
+pub fn _log(level: kmesg::Level, args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;   // new
 
+    let (color, reset) = level_color(level);
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        WRITER.lock().write_fmt(format_args!("{}{}{}", color, args, reset)).unwrap();
+        crate::uart::print(format_args!("{}{}{}", color, args, reset));
+        kmesg::log(level, args);
     });
+}
+
+/// The escapes that color text logged at `level` on the screen and the
+/// serial port, and put the color back after.
+fn level_color(level: kmesg::Level) -> (&'static str, &'static str) {
+    match level {
+        kmesg::Level::Error => ("\x1b[91m", "\x1b[0m"),
+        kmesg::Level::Warn => ("\x1b[93m", "\x1b[0m"),
+        kmesg::Level::Info => ("", ""),
+        kmesg::Level::Debug => ("\x1b[90m", "\x1b[0m"),
+    }
 }
\ No newline at end of file